@@ -1,6 +1,7 @@
 use std::io;
 use std::io::Read;
 use std::process;
+use crate::layout::Layout;
 //TODO: change comments to Pascal Like?`{}`
 
 fn abort(msg: &str) {
@@ -18,6 +19,8 @@ mod lexer {
         // DELIMITERS
         Point,
         Comma,
+        Colon,
+        Caret,
         Semic,
         LParen,
         RParen,
@@ -54,6 +57,9 @@ mod lexer {
         Const,
         Var,
         Procedure,
+        Function,
+        Interrupt,
+        Return,
         Forward,
         Call,
         Begin,
@@ -63,20 +69,45 @@ mod lexer {
         Else,
         While,
         Do,
+        Repeat,
+        Until,
+        Break,
+        Continue,
+        Case,
+        Of,
+        DotDot,
+        For,
+        To,
+        Downto,
+        By,
         Size,
+        Type,
+        Record,
+        Array,
+        New,
+        Dispose,
+        Nil,
+        Import,
+        Uses,
         Exit,
-        Str(String),
+        True,
+        False,
+        Str(Vec<u8>),
+        Asm(String), // the text of an asm block, as written
 
         Number{val: i32},
         Ident{val: String}
     }
 
     impl Token {
-        fn from_str(input: &str) -> Token {
+        fn from_str(input: &str) -> Result<Token, String> {
             let lowercase = input.to_lowercase();
-            match lowercase.as_str() {
+            Ok(match lowercase.as_str() {
                 "."=> Token::Point,
+                ".."=> Token::DotDot,
                 ","=> Token::Comma,
+                ":"=> Token::Colon,
+                "^"=> Token::Caret,
                 ";"=> Token::Semic,
                 "("=> Token::LParen,
                 ")"=> Token::RParen,
@@ -113,6 +144,9 @@ mod lexer {
                 "const"=> Token::Const,
                 "var"=> Token::Var,
                 "procedure"=> Token::Procedure,
+                "function"=> Token::Function,
+                "interrupt"=> Token::Interrupt,
+                "return"=> Token::Return,
                 "forward" => Token::Forward,
                 "call"=> Token::Call,
                 "begin"=> Token::Begin,
@@ -122,43 +156,185 @@ mod lexer {
                 "else"=> Token::Else,
                 "while"=> Token::While,
                 "do"=> Token::Do,
+                "repeat"=> Token::Repeat,
+                "until"=> Token::Until,
+                "break"=> Token::Break,
+                "continue"=> Token::Continue,
+                "case"=> Token::Case,
+                "of"=> Token::Of,
+                "for"=> Token::For,
+                "to"=> Token::To,
+                "downto"=> Token::Downto,
+                "by"=> Token::By,
                 "size"=> Token::Size,
-                _ => match input.parse::<i32>() {
-                    Ok(n) => Token::Number{val: n},
-                    Err(_) => Token::Ident{val: input.to_string()}
+                "type"=> Token::Type,
+                "record"=> Token::Record,
+                "array"=> Token::Array,
+                "new"=> Token::New,
+                "dispose"=> Token::Dispose,
+                "nil"=> Token::Nil,
+                "import"=> Token::Import,
+                "uses"=> Token::Uses,
+                "true"=> Token::True,
+                "false"=> Token::False,
+                _ => match number(&lowercase) {
+                    Some(n) => Token::Number{val: n?},
+                    None => Token::Ident{val: input.to_string()}
                 }
+            })
+        }
+    }
+
+    // Numeric literals: decimal, hexadecimal (0x or $), binary (0b) and octal (0o),
+    // with optional '_' digit separators. Anything that fits in 32 bits is accepted,
+    // so 0xffffffff is the same word as -1
+    pub fn number(input: &str) -> Option<Result<i32, String>> {
+        let (digits, radix) = if let Some(d) = input.strip_prefix("0x") {
+            (d, 16)
+        } else if let Some(d) = input.strip_prefix('$') {
+            (d, 16)
+        } else if let Some(d) = input.strip_prefix("0b") {
+            (d, 2)
+        } else if let Some(d) = input.strip_prefix("0o") {
+            (d, 8)
+        } else if input.starts_with(|c: char| c.is_ascii_digit()) {
+            (input, 10)
+        } else {
+            return None
+        };
+
+        let digits = digits.replace('_', "");
+        if digits.is_empty() {
+            return Some(Err(format!("invalid numeric literal '{input}'")));
+        }
+
+        Some(match u32::from_str_radix(&digits, radix) {
+            Ok(n) => Ok(n as i32),
+            Err(e) if *e.kind() == std::num::IntErrorKind::PosOverflow => Err(format!("numeric literal '{input}' does not fit in 32 bits")),
+            Err(_) => Err(format!("invalid numeric literal '{input}'"))
+        })
+    }
+
+    // Decodes the body of a string literal. Strings are byte strings: escapes
+    // may produce any byte, other characters keep their UTF-8 encoding
+    fn unescape(raw: &str, lineno: usize) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![];
+        let mut chars = raw.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                continue;
+            }
+
+            match chars.next() {
+                Some('n') => bytes.push(b'\n'),
+                Some('t') => bytes.push(b'\t'),
+                Some('0') => bytes.push(0),
+                Some('\\') => bytes.push(b'\\'),
+                Some('\'') => bytes.push(b'\''),
+                Some('x') => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    match u8::from_str_radix(&hex, 16) {
+                        Ok(b) if hex.len() == 2 => bytes.push(b),
+                        _ => return Err(format!("Lexical error (line {lineno}): '\\x' must be followed by two hexadecimal digits, got '{hex}'"))
+                    }
+                }
+                Some(c) => return Err(format!("Lexical error (line {lineno}): unknown escape sequence '\\{c}' in string")),
+                None => return Err(format!("Lexical error (line {lineno}): unterminated escape sequence in string")),
             }
         }
+        Ok(bytes)
+    }
+
+    // The word starting with c, without taking the rest of it from the source
+    fn word(c: char, rest: &std::iter::Peekable<std::str::Chars>) -> String {
+        std::iter::once(c).chain(rest.clone().take_while(|c| c.is_ascii_alphanumeric() || *c == '_')).collect()
     }
 
-    pub fn tokenize(source: String) -> Vec<(Token, usize, usize)> {
+    pub fn tokenize(source: String) -> Result<Vec<(Token, usize, usize)>, String> {
         let mut tokens: Vec<(Token, usize, usize)> = Vec::new();
-        let symbols = [".", ",", ";","(",")","?","!","#","+","-","*","/","=","<",">", "[", "]", "{", "}"];
-        let mut source = source;
+        let symbols = [".", ",", ":", ";", "^","(",")","?","!","#","+","-","*","/","=","<",">", "[", "]", "{", "}"];
+
+        // Pull string literals out of the source, leaving a placeholder identifier,
+        // so their contents are not split or taken for comments. Quotes inside comments
+        // do not start a string. A quote is written '' or \'
+        // The text of asm blocks is pulled out the same way, up to their end
         let mut strings = vec![];
-        let mut current = String::new();
-        let mut recording = false;
-        for c in source.chars() {
-            if c == '\'' {
-                if recording {
-                    strings.push(current);
-                    current = String::new();
-                }
-                recording = !recording;
-            } else if recording {
-                current.push(c);
+        let mut blocks = vec![];
+        let mut stripped = String::new();
+        let mut chars = source.chars().peekable();
+        let mut lineno = 1;
+        let mut comment: Option<char> = None; // the character that closes the current comment
+        while let Some(c) = chars.next() {
+            if c == '\n' { lineno += 1; }
+
+            if let Some(close) = comment {
+                if c == close { comment = None; }
+                stripped.push(c);
+                continue;
             }
-        }
 
-        for (i, string) in strings.iter().enumerate() {
-            source = source.replace(&format!("'{string}'"), &format!("___pl0__reserved__identifier__string__{i}"));
+            match c {
+                '{' => comment = Some('}'),
+                '/' if chars.peek() == Some(&'/') => comment = Some('\n'),
+                '\'' => {
+                    let mut raw = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('\'') if chars.peek() == Some(&'\'') => {
+                                chars.next();
+                                raw.push_str("\\'");
+                            }
+                            Some('\'') => break,
+                            Some('\\') => {
+                                raw.push('\\');
+                                if let Some(e) = chars.next_if(|&e| e != '\n') { raw.push(e) }
+                            }
+                            Some('\n') | None => return Err(format!("Lexical error (line {lineno}): unterminated string")),
+                            Some(c) => raw.push(c),
+                        }
+                    }
+                    stripped.push_str(&format!(" ___pl0__reserved__identifier__string__{} ", strings.len()));
+                    strings.push(unescape(&raw, lineno)?);
+                    continue;
+                }
+                c if !stripped.ends_with(|p: char| p.is_ascii_alphanumeric() || p == '_')
+                    && word(c, &chars).eq_ignore_ascii_case("asm") => {
+                    chars.nth(1);
+                    let start = lineno;
+                    let mut text = String::new();
+                    loop {
+                        match chars.next() {
+                            Some(c) if !text.ends_with(|p: char| p.is_ascii_alphanumeric() || p == '_' || p == '.')
+                                && word(c, &chars).eq_ignore_ascii_case("end") => {
+                                chars.nth(1);
+                                break;
+                            }
+                            Some(c) => {
+                                if c == '\n' { lineno += 1; }
+                                text.push(c);
+                            }
+                            None => return Err(format!("Lexical error (line {start}): asm block without end")),
+                        }
+                    }
+                    // keep the lines of the block, so the tokens after it keep theirs
+                    stripped.push_str(&format!(" ___pl0__reserved__identifier__asm__{} ", blocks.len()));
+                    stripped.push_str(&"\n".repeat(text.matches('\n').count()));
+                    blocks.push(text);
+                    continue;
+                }
+                _ => ()
+            }
+            stripped.push(c);
         }
+        let mut source = stripped;
 
         for symbol in symbols.iter() {
             source = source.replace(symbol, &format!(" {symbol} "));
         }
-        for symbol in [">  =","<  =",": =", "/  /", "<  >"].iter() {
-            let first = symbol.chars().nth(0).unwrap();
+        for symbol in [">  =","<  =",":  =", "/  /", "<  >", ".  ."].iter() {
+            let first = symbol.chars().next().unwrap();
             let last = symbol.chars().last().unwrap();
             source = source.replace(symbol, &format!("{first}{last}"));
         }
@@ -179,7 +355,9 @@ mod lexer {
                     }
                     _ => {
                         if !is_comment {
-                            tokens.push((Token::from_str(word), lineno + 1, col)); // start lines from 1
+                            let tok = Token::from_str(word)
+                                .map_err(|e| format!("Lexical error {:?}: {e}", (lineno + 1, col)))?;
+                            tokens.push((tok, lineno + 1, col)); // start lines from 1
                             col += word.len()
                         } else {
                             col += word.len()
@@ -198,6 +376,9 @@ mod lexer {
                         let i = i.parse::<usize>().unwrap();
                         let string = strings[i].clone();
                         final_tokens.push((Token::Str(string), tok.1, tok.2));
+                    } else if let Some(i) = val.strip_prefix("___pl0__reserved__identifier__asm__") {
+                        let text = blocks[i.parse::<usize>().unwrap()].clone();
+                        final_tokens.push((Token::Asm(text), tok.1, tok.2));
                     } else {
                         final_tokens.push((tok.0.clone(), tok.1, tok.2))
                     }
@@ -206,29 +387,189 @@ mod lexer {
             }
         }
 
-        return final_tokens
+        Ok(final_tokens)
     }
 }
 
 mod parser {
 
     // The parser constructs a syntax tree of the program
-    use super::lexer::Token;
+    use super::lexer::{Token, tokenize};
+    use std::mem;
+    use std::path::{Path, PathBuf};
+    use std::sync::OnceLock;
+
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum Type {
+        Integer,
+        Char,
+        Boolean, // a word holding 0 or 1
+        Record{id: usize, size: i32}, // index in Scanner::records
+        Pointer(usize), // index in Scanner::pointers, which holds what it points to
+        Nil
+    }
+
+    impl Type {
+        // Bytes taken by one element of an array of this type
+        pub fn size(&self) -> i32 {
+            match self {
+                Type::Integer | Type::Boolean | Type::Pointer(_) | Type::Nil => 4,
+                Type::Char => 1,
+                Type::Record{size, ..} => *size
+            }
+        }
+
+        pub fn load(&self) -> &'static str {
+            match self {
+                Type::Integer | Type::Boolean | Type::Pointer(_) | Type::Nil => "lw",
+                Type::Char => "lbu",
+                Type::Record{..} => unreachable!("records have no value as a whole")
+            }
+        }
+
+        pub fn store(&self) -> &'static str {
+            match self {
+                Type::Integer | Type::Boolean | Type::Pointer(_) | Type::Nil => "sw",
+                Type::Char => "sb",
+                Type::Record{..} => unreachable!("records have no value as a whole")
+            }
+        }
+
+        pub fn name(&self) -> &'static str {
+            match self {
+                Type::Integer => "integer",
+                Type::Char => "char",
+                Type::Boolean => "boolean",
+                Type::Record{..} => "a record",
+                Type::Pointer(_) => "a pointer",
+                Type::Nil => "nil"
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Array {
+        pub name: String,
+        pub element: Type,
+        pub dims: Vec<i32>,
+        pub lengths: bool, // whether the lengths are stored beside it, fields of records do not have them
+        pub open: bool // a parameter: its label holds the address of the argument, and the length is set by each call
+    }
+
+    // Fields are laid out in order, words aligned to 4 bytes and chars packed
+    #[derive(Debug, Clone)]
+    pub struct Record {
+        pub name: String,
+        pub fields: Vec<Field>
+    }
+
+    // A variable of a routine, kept on the stack across the calls of recursive routines
+    #[derive(Debug, Clone)]
+    pub struct Static {
+        pub scope: String,
+        pub label: String,
+        pub bytes: Option<i32>, // None for arrays
+        pub pos: (usize, usize)
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Field {
+        pub name: String,
+        pub element: Type,
+        pub dims: Vec<i32>, // empty unless the field is an array
+        pub offset: i32
+    }
+
+    // What a designator refers to. Unless it is a plain variable, its address is in T
+    pub struct Place {
+        pub name: String,
+        pub element: Type,
+        pub array: Option<Array>,
+        pub memory: bool
+    }
+
+    impl Place {
+        pub fn is_value(&self) -> bool {
+            self.array.is_none() && !matches!(self.element, Type::Record{..})
+        }
+    }
+
+    impl Array {
+        // Total number of elements, arrays are laid out in row-major order
+        pub fn elements(&self) -> i32 {
+            self.dims.iter().product()
+        }
+
+        // Label of the word holding the length of a dimension
+        pub fn len_label(&self, dim: usize) -> String {
+            match dim {
+                0 => format!("{}.len", self.name),
+                _ => format!("{}.len{dim}", self.name)
+            }
+        }
+    }
+
+    // Parameters are static variables of the procedure, the caller stores the arguments into them
+    #[derive(Debug, Clone)]
+    pub struct Signature {
+        pub name: String,
+        pub params: Vec<(String, Type, bool)>, // whether the parameter is an array of that type
+        pub result: Option<Type> // functions only
+    }
+
+    impl Signature {
+        pub fn kind(&self) -> &'static str {
+            match self.result {
+                Some(_) => "function",
+                None => "procedure"
+            }
+        }
+    }
+
+    // Code generation switches, set from the command line
+    #[derive(Debug, Default, Clone)]
+    pub struct Options {
+        pub bounds_check: bool,
+        pub stack_check: bool,
+        pub div_check: bool,
+        pub path: Vec<PathBuf> // where units are searched after the directory of the importing file
+    }
+
     pub struct Scanner {
         nesting: usize,
         cursor: usize,
         tokens: Vec<Token>,
         pos: Vec<(usize, usize)>,
-        constants: Vec<String>,
-        arrays: Vec<String>,
+        constants: Vec<(String, i32)>,
+        arrays: Vec<Array>,
+        loop_variables: Vec<String>,
+        loops: Vec<(String, String)>, // labels `continue` and `break` jump to
+        signatures: Vec<Signature>,
+        types: Vec<(String, Type)>, // of scalar variables, when not integer
+        records: Vec<Record>,
+        pointers: Vec<Option<Type>>, // None until a type declared later in the same section is known
+        unresolved: Vec<(usize, String, (usize, usize))>,
+        forward_types: bool, // pointers may name types declared later, only in a type section
+        type_names: Vec<(String, Type)>,
+        routines: Vec<(String, Option<Type>)>, // epilogue of the enclosing procedures, and the result of functions
+        interrupts: Vec<(String, String)>, // handlers and the interrupt each one is installed for
+        statics: Vec<Static>,
+        calls: Vec<(String, String)>, // caller and callee, to find the routines that are recursive
         local_constants: usize,
+        unit: String, // empty in the program itself
+        dir: PathBuf,
+        units: Vec<(String, PathBuf)>,
+        importing: Vec<PathBuf>,
+        visible: Vec<String>, // units imported by the file being compiled
+        warnings: Vec<String>,
         pub scope_name: String,
         pub scope: Vec<String>,
-        pub indentation: String
+        pub indentation: String,
+        pub options: Options
     }
 
     impl Scanner {
-        pub fn new(tokens: Vec<(Token, usize, usize)>, indentation: String) -> Self {
+        pub fn new(tokens: Vec<(Token, usize, usize)>, indentation: String, options: Options) -> Self {
             let mut pos = Vec::new();
             let mut tokens_only = Vec::new();
             for (tok, lineno, tokno) in tokens {
@@ -241,27 +582,130 @@ mod parser {
                 cursor: 0,
                 tokens: tokens_only,
                 pos,
-                constants: vec![],
+                constants: vec![], // the devices, see compile()
                 arrays: vec![],
+                loop_variables: vec![],
+                loops: vec![],
+                signatures: vec![],
+                types: vec![],
+                records: vec![],
+                pointers: vec![],
+                unresolved: vec![],
+                forward_types: false,
+                type_names: vec![],
+                routines: vec![],
+                interrupts: vec![],
+                statics: vec![],
+                calls: vec![],
                 local_constants: 0,
+                unit: String::new(),
+                dir: PathBuf::new(),
+                units: vec![],
+                importing: vec![],
+                visible: vec![],
+                warnings: vec![],
                 scope_name: "global".to_string(),
                 scope: vec![],
-                indentation
+                indentation,
+                options
             }
         }
 
-        pub fn is_array(&self, id: String) -> Result<(), String> {
-            if !self.arrays.contains(&id) {
-                return Err(format!("Error {:?}: {id} is not an array", self.pos[self.cursor()]));
+        pub fn array(&self, id: &str) -> Result<Array, String> {
+            match self.arrays.iter().find(|a| a.name == id) {
+                Some(array) => Ok(array.clone()),
+                None => Err(format!("Type error {:?}: {} is not an array", self.pos[self.cursor()], id.replace("global.", "")))
             }
+        }
 
+        // Fails for symbols a statement cannot store into
+        pub fn writable(&self, id: &str) -> Result<(), String> {
+            if self.is_constant(id) {
+                return Err(format!("Error {:?}: Cannot assign value to constant", self.pos[self.cursor()]));
+            }
+            match self.signature(id) {
+                Some(sig) if sig.result.is_some() => return Err(format!("Error {:?}: {} is a function, its result is given with 'return'", self.pos[self.cursor()], id.replace("global.", ""))),
+                Some(_) => return Err(format!("Type error {:?}: {} is a procedure, not a variable", self.pos[self.cursor()], id.replace("global.", ""))),
+                None => ()
+            }
+            if self.loop_variables.iter().any(|v| v == id) {
+                return Err(format!("Error {:?}: {} is the variable of an enclosing for loop and cannot be assigned", self.pos[self.cursor()], id.replace("global.", "")));
+            }
             Ok(())
         }
 
+        // Reserves a word in the current scope for a value the generated code keeps
+        // between statements, and returns its label
+        pub fn hidden_variable(&mut self, asm: &mut Vec<String>, name: String) -> String {
+            let depth = self.scope_name.split('.').count() - 1;
+            self.emit(asm, format!("#[pragma(var)] {scope}; {n}{name}: #res 4", scope = self.scope_name, n = ".".repeat(depth)));
+            let label = format!("{}.{name}", self.scope_name);
+            self.statics.push(Static { scope: self.scope_name.clone(), label: label.clone(), bytes: Some(4), pos: self.pos[self.cursor() - 1] });
+            label
+        }
+
+        pub fn type_of(&self, id: &str) -> Type {
+            if let Some(array) = self.arrays.iter().find(|a| a.name == id) {
+                return array.element;
+            }
+            if let Some(result) = self.signature(id).and_then(|sig| sig.result) {
+                return result;
+            }
+            self.types.iter().rev().find(|(name, _)| name == id).map_or(Type::Integer, |&(_, t)| t)
+        }
+
+        pub fn record(&self, t: Type) -> Option<&Record> {
+            match t {
+                Type::Record{id, ..} => self.records.get(id),
+                _ => None
+            }
+        }
+
+        // Whether values of two types can be assigned and compared to each other
+        pub fn compatible(&self, a: Type, b: Type) -> bool {
+            match (a, b) {
+                (Type::Integer | Type::Char, Type::Integer | Type::Char) => true,
+                (Type::Boolean, Type::Boolean) => true,
+                (Type::Pointer(_) | Type::Nil, Type::Nil) | (Type::Nil, Type::Pointer(_)) => true,
+                (Type::Pointer(a), Type::Pointer(b)) => self.pointers[a] == self.pointers[b],
+                _ => false
+            }
+        }
+
+        // The type a pointer points to, known once its type section is over
+        pub fn target(&self, pointer: usize, pos: (usize, usize)) -> Result<Type, String> {
+            self.pointers[pointer].ok_or(format!("Error {:?}: the type this pointer points to is not declared", pos))
+        }
+
+        // Types are looked up from the innermost scope out, like constants
+        pub fn search_type(&self, id: &str) -> Option<Type> {
+            let mut scopes: Vec<&str> = self.scope_name.split(".").collect();
+            while !scopes.is_empty() {
+                let name = format!("{n}.{id}", n = scopes.join("."));
+                if let Some(&(_, t)) = self.type_names.iter().rev().find(|(n, _)| *n == name) {
+                    return Some(t);
+                }
+                _ = scopes.pop();
+            }
+            None
+        }
+
+        pub fn signature(&self, id: &str) -> Option<&Signature> {
+            self.signatures.iter().rev().find(|sig| sig.name == id)
+        }
+
+        pub fn is_constant(&self, id: &str) -> bool {
+            self.constants.iter().any(|(name, _)| name == id)
+        }
+
+        pub fn constant_value(&self, id: &str) -> Option<i32> {
+            self.constants.iter().rev().find(|(name, _)| name == id).map(|&(_, val)| val)
+        }
+
         pub fn search(&self, id: String) -> Result<String, String> {
             let mut scopes: Vec<&str> = self.scope_name.split(".").collect();
 
-            while scopes.len() > 0 {
+            while !scopes.is_empty() {
                 let name = format!("{n}.{id}", n = scopes.join("."));
                 if self.scope.contains(&name) {
                     return Ok(name);
@@ -280,9 +724,9 @@ mod parser {
         pub fn search_const(&self, id: String) -> Result<String, String> {
             let mut scopes: Vec<&str> = self.scope_name.split(".").collect();
 
-            while scopes.len() > 0 {
+            while !scopes.is_empty() {
                 let name = format!("{n}.{id}", n = scopes.join("."));
-                if self.constants.contains(&name) {
+                if self.is_constant(&name) {
                     return Ok(name);
                 } else {
                     _ = scopes.pop();
@@ -296,6 +740,32 @@ mod parser {
             }
         }
 
+        // Warnings do not stop the compilation. Like errors, those of units are prefixed with their file
+        pub fn warn(&mut self, pos: (usize, usize), msg: &str) {
+            self.warnings.push(format!("Warning {:?}: {msg}", pos));
+        }
+
+        // Labels of the data section are global, so those of units carry the unit's name
+        pub fn label(&self, kind: &str, pos: (usize, usize)) -> String {
+            if self.unit.is_empty() {
+                format!("{kind}_{}_{}", pos.0, pos.1)
+            } else {
+                format!("{kind}_{}_{}_{}", self.unit, pos.0, pos.1)
+            }
+        }
+
+        // Units are labelled by their name, so nothing else in the program may take it
+        // and the constants of sys.asm are defined by the runtime already
+        pub fn declarable(&self, qualified_id: &str, pos: (usize, usize)) -> Result<(), String> {
+            if let Some((name, _)) = self.units.iter().find(|(name, _)| format!("global.{name}") == qualified_id) {
+                return Err(format!("Error {:?}: {name} is the name of a unit", pos));
+            }
+            match devices()?.iter().find(|(name, _)| name == qualified_id) {
+                Some((name, _)) => Err(format!("Error {:?}: {} is a constant of sys.asm", pos, name.replace("global.", ""))),
+                None => Ok(())
+            }
+        }
+
         pub fn scope_drop(&mut self, until: String) {
             while self.scope.last() != Some(&until) {
                 self.scope.pop();
@@ -331,7 +801,7 @@ mod parser {
                 None => None,
                 Some(t) => {
                     self.cursor += 1;
-                    Some(&t)
+                    Some(t)
                 }
             }
         }
@@ -357,6 +827,27 @@ mod parser {
             }
         }
 
+        // An identifier, or one qualified by the name of an imported unit: strings.concat
+        pub fn expect_name(&mut self) -> Result<String, String> {
+            let unit = self.expect_ident()?;
+            if !self.visible.contains(&unit) || !self.is_match(Token::Point)
+                || !matches!(self.tokens.get(self.cursor + 1), Some(Token::Ident{..})) {
+                return Ok(unit);
+            }
+            self.pop();
+            let pos = self.pos[self.cursor];
+            let name = self.expect_ident()?;
+            let qualified_id = format!("global.{unit}.{name}");
+            if self.is_constant(&qualified_id) || self.signature(&qualified_id).is_some()
+                || self.type_names.iter().any(|(n, _)| *n == qualified_id) {
+                Ok(format!("{unit}.{name}"))
+            } else if self.scope.contains(&qualified_id) {
+                Err(format!("Error {:?}: {name} is a variable of unit {unit}, only its constants, types and routines are exported", pos))
+            } else {
+                Err(format!("Error {:?}: unit {unit} has no {name}", pos))
+            }
+        }
+
         pub fn expect_num(&mut self) -> Result<i32, String> {
             match self.pop().unwrap() {
                 &Token::Number{val} => Ok(val),
@@ -374,36 +865,336 @@ mod parser {
     pub const B: &str = "a3";
     pub const T: &str = "a4";
 
+    // All of them, as master.asm names them
+    pub const REGISTERS: [&str; 32] = ["zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+        "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6"];
+
+    // Those of the generated code and of the runtime, which interrupt handlers save
+    pub const INTERRUPT_SAVED: [&str; 12] = ["ra", "a0", "a1", A, B, T, "t1", "t2", "t3", "t4", "t5", "t6"];
+
+    // Escapes a byte string for a customasm `#d "..."` directive: printable ASCII
+    // is written as is, quotes, backslashes, newlines and tabs are escaped and every
+    // other byte is spelled out as \xNN, so the assembler never reinterprets the contents
+    pub fn asm_string(s: &[u8]) -> String {
+        let mut escaped = String::new();
+        for &b in s {
+            match b {
+                b'"' => escaped.push_str("\\\""),
+                b'\\' => escaped.push_str("\\\\"),
+                b'\n' => escaped.push_str("\\n"),
+                b'\t' => escaped.push_str("\\t"),
+                b' '..=b'~' => escaped.push(b as char),
+                _ => escaped.push_str(&format!("\\x{b:02x}")),
+            }
+        }
+        escaped
+    }
+
+
+    // The warnings found are kept even when the compilation fails
+    pub fn compile(indentation: &str, tokens: Vec<(Token, usize, usize)>, asm: &mut Vec<String>, warnings: &mut Vec<String>, options: Options) -> Result<Vec<String>, String> {
+        let mut scanner = Scanner::new(tokens, indentation.to_owned(), options);
+        scanner.constants = devices()?.to_vec();
+        let compiled = program(&mut scanner, asm);
+        warnings.append(&mut scanner.warnings);
+        compiled?;
+        frames(&scanner, asm)
+    }
+
+    // The routines a routine calls, directly or through others
+    fn reachable(scanner: &Scanner, from: &str) -> Vec<String> {
+        let mut reached: Vec<String> = vec![];
+        let mut pending = vec![from.to_owned()];
+        while let Some(caller) = pending.pop() {
+            for (_, callee) in scanner.calls.iter().filter(|(c, _)| *c == caller) {
+                if !reached.contains(callee) {
+                    reached.push(callee.clone());
+                    pending.push(callee.clone());
+                }
+            }
+        }
+        reached
+    }
+
+    /* Variables are static, so a routine that calls itself, directly or through others,
+       would overwrite those of the calls still running. Every call to such a routine keeps
+       its parameters and variables on the stack, and puts them back when it returns.
+       Other calls only drop their markers */
+    fn frames(scanner: &Scanner, asm: &[String]) -> Result<Vec<String>, String> {
+        let mut recursive: Vec<&str> = scanner.calls.iter().map(|(_, callee)| callee.as_str())
+            .filter(|&callee| reachable(scanner, callee).iter().any(|r| r == callee))
+            .collect();
+        recursive.dedup();
+        for routine in &recursive {
+            if let Some(array) = scanner.statics.iter().find(|s| s.scope == *routine && s.bytes.is_none()) {
+                return Err(format!("Error {:?}: {} calls itself, but the array {} is static and too large to keep for each call",
+                    array.pos, routine.replace("global.", ""), array.label.replace("global.", "")));
+            }
+        }
 
-    pub fn compile(indentation: &str, tokens: Vec<(Token, usize, usize)>, asm: &mut Vec<String>) -> Result<Vec<String>, String> {
-        let mut scanner = Scanner::new(tokens, indentation.to_owned());
-        program(&mut scanner, asm)?;
-        Ok(asm.clone())
+        let mut linked = vec![];
+        for line in asm {
+            let (indentation, marker) = line.split_at(line.len() - line.trim_start().len());
+            let (save, routine) = match (marker.strip_prefix("#[pragma(save)] "), marker.strip_prefix("#[pragma(restore)] ")) {
+                (Some(routine), _) => (true, routine),
+                (_, Some(routine)) => (false, routine),
+                _ => {
+                    linked.push(line.clone());
+                    continue;
+                }
+            };
+            if !recursive.contains(&routine) {
+                continue;
+            }
+            let slots: Vec<(&String, i32)> = scanner.statics.iter()
+                .filter(|s| s.scope == routine)
+                .map(|s| (&s.label, s.bytes.unwrap()))
+                .collect();
+            let mut emit = |s: String| linked.push(format!("{indentation}{s}"));
+            if save {
+                for (label, bytes) in slots {
+                    if bytes == 4 {
+                        emit(format!("llw t1, {label}"));
+                        emit("push t1, sp".to_string());
+                    } else { // records, a word at a time
+                        emit(format!("la t1, {label}"));
+                        for word in 0..bytes / 4 {
+                            emit(format!("lw t2, {}(t1)", word * 4));
+                            emit("push t2, sp".to_string());
+                        }
+                    }
+                }
+            } else {
+                for (label, bytes) in slots.into_iter().rev() {
+                    if bytes == 4 {
+                        emit("pop t1, sp".to_string());
+                        emit(format!("ssw t1, {label}, t2"));
+                    } else {
+                        emit(format!("la t1, {label}"));
+                        for word in (0..bytes / 4).rev() {
+                            emit("pop t2, sp".to_string());
+                            emit(format!("sw t2, {}(t1)", word * 4));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(linked)
     }
 
+    // program = { import } block "." ;
     fn program(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        imports(scanner, asm)?;
         block(scanner, asm)?;
         
         if !scanner.is_done() {
             scanner.expect(&Token::Point)?;
             if !scanner.is_done() {
-                return Err(format!("Tokens after '.' (POINT)"));
+                return Err("Tokens after '.' (POINT)".to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /* import = "import" string ";" | "uses" ident { "," ident } ";" ;
+       uses strings finds the file strings.pl0 like import 'strings.pl0' would */
+    fn imports(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        while scanner.is_match(Token::Import) || scanner.is_match(Token::Uses) {
+            if scanner.pop() == Some(&Token::Import) {
+                let pos = scanner.pos[scanner.cursor()];
+                let file = match scanner.pop() {
+                    Some(Token::Str(file)) => String::from_utf8_lossy(file).into_owned(),
+                    Some(Token::Ident{val}) if val.starts_with('"') =>
+                        return Err(format!("Syntax error {:?}: the file name of a unit goes in single quotes, import 'file.pl0'", pos)),
+                    tok => return Err(format!("Syntax error {:?}: Expected the file name of a unit, got {:?}", pos, tok))
+                };
+                import(scanner, asm, Path::new(&file), pos)?;
+            } else {
+                loop {
+                    let pos = scanner.pos[scanner.cursor()];
+                    let name = scanner.expect_ident()?;
+                    import(scanner, asm, Path::new(&format!("{name}.pl0")), pos)?;
+                    if !scanner.is_match(Token::Comma) {
+                        break;
+                    }
+                    scanner.pop();
+                }
+            }
+            scanner.expect(&Token::Semic)?;
+        }
+        Ok(())
+    }
+
+    // The library shipped with the compiler, in std/ beside the runtime
+    const LIBRARY: [(&str, &str); 3] = [
+        ("arrays.pl0", include_str!("../std/arrays.pl0")),
+        ("math.pl0", include_str!("../std/math.pl0")),
+        ("strings.pl0", include_str!("../std/strings.pl0")),
+    ];
+
+    /* The constants of the devices, as sys.asm names them, are global constants of every
+       program: NAME = value, where the value adds and multiplies numbers and earlier names.
+       sys.asm is built into the compiler, so it is read once */
+    fn devices() -> Result<&'static [(String, i32)], String> {
+        static DEVICES: OnceLock<Result<Vec<(String, i32)>, String>> = OnceLock::new();
+        DEVICES.get_or_init(|| device_constants(include_str!("../std/sys.asm"))).as_deref().map_err(String::clone)
+    }
+
+    pub fn device_constants(sys: &str) -> Result<Vec<(String, i32)>, String> {
+        let mut constants: Vec<(String, i32)> = vec![];
+        for (n, line) in sys.lines().enumerate() {
+            let line = line.split(';').next().unwrap();
+            let Some((name, value)) = line.split_once('=') else { continue };
+            let mut sum: i32 = 0;
+            for term in value.split('+') {
+                let mut product: i32 = 1;
+                for factor in term.split('*').map(str::trim) {
+                    let value = match factor.strip_prefix("0x") {
+                        Some(hex) => i32::from_str_radix(hex, 16).ok(),
+                        None => factor.parse().ok().or_else(|| {
+                            constants.iter().find(|(n, _)| *n == format!("global.{factor}")).map(|&(_, value)| value)
+                        })
+                    };
+                    let value = value.ok_or(format!("Error: sys.asm, line {}: cannot evaluate {factor} in {}", n + 1, name.trim()))?;
+                    product = product.wrapping_mul(value);
+                }
+                sum = sum.wrapping_add(product);
+            }
+            constants.push((format!("global.{}", name.trim()), sum));
+        }
+        Ok(constants)
+    }
+
+    // Units are looked for beside the importing file, then along the search path,
+    // then in the library, and compiled once however many files import them
+    fn import(scanner: &mut Scanner, asm: &mut Vec<String>, file: &Path, pos: (usize, usize)) -> Result<(), String> {
+        let found = std::iter::once(&scanner.dir).chain(&scanner.options.path)
+            .map(|dir| dir.join(file))
+            .find(|path| path.is_file());
+        let (path, canonical, source) = match found {
+            Some(path) => {
+                let canonical = path.canonicalize().map_err(|err| format!("Error {:?}: cannot open {}: {err}", pos, path.display()))?;
+                let source = std::fs::read_to_string(&path).map_err(|err| format!("Error {:?}: cannot read {}: {err}", pos, path.display()))?;
+                (path, canonical, source)
+            }
+            None => match LIBRARY.iter().find(|(name, _)| Path::new(name) == file) {
+                Some((name, source)) => {
+                    let path = Path::new("<std>").join(name); // not a directory, so its units only import others of the library
+                    (path.clone(), path, source.to_string())
+                }
+                None => return Err(format!("Error {:?}: cannot find unit {}", pos, file.display()))
+            }
+        };
+        let name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default().to_owned();
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic()) || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("Error {:?}: the unit {} must be named like an identifier", pos, path.display()));
+        }
+        if ["crt0", "stack", "stack_limit", "heap", "heap_end"].contains(&name.as_str()) {
+            return Err(format!("Error {:?}: {name} is used by the runtime and cannot name a unit", pos));
+        }
+
+        if let Some(i) = scanner.importing.iter().position(|p| *p == canonical) {
+            let cycle: Vec<_> = scanner.importing[i..].iter().chain([&canonical])
+                .map(|p| p.file_stem().unwrap().to_string_lossy())
+                .collect();
+            return Err(format!("Error {:?}: units import each other: {}", pos, cycle.join(" -> ")));
+        }
+        match scanner.units.iter().find(|(n, _)| *n == name) {
+            Some((_, p)) if *p == canonical => {},
+            Some((_, p)) => return Err(format!("Error {:?}: {} and {} are both units named {name}", pos, p.display(), path.display())),
+            None => {
+                let warnings = scanner.warnings.len();
+                let compiled = unit(scanner, asm, &name, &path, &canonical, source);
+                for warning in &mut scanner.warnings[warnings..] {
+                    *warning = format!("{}: {warning}", path.display());
+                }
+                compiled.map_err(|err| format!("{}: {err}", path.display()))?;
+                scanner.units.push((name.clone(), canonical));
             }
         }
+        if !scanner.visible.contains(&name) {
+            scanner.visible.push(name);
+        }
+        Ok(())
+    }
+
+    /* unit = { import } declarations "." ;
+       A unit is compiled with its own tokens in the scope global.name, and its labels
+       nest under its name like those of a procedure. The file importing it carries on after */
+    fn unit(scanner: &mut Scanner, asm: &mut Vec<String>, name: &str, path: &Path, canonical: &Path, source: String) -> Result<(), String> {
+        let (tokens, pos) = tokenize(source)?.into_iter().map(|(tok, line, col)| (tok, (line, col))).unzip();
+        let tokens = mem::replace(&mut scanner.tokens, tokens);
+        let pos = mem::replace(&mut scanner.pos, pos);
+        let cursor = mem::replace(&mut scanner.cursor, 0);
+        let unit = mem::replace(&mut scanner.unit, name.to_owned());
+        let dir = mem::replace(&mut scanner.dir, path.parent().unwrap_or(Path::new("")).to_owned());
+        let visible = mem::take(&mut scanner.visible);
+        scanner.importing.push(canonical.to_owned());
+
+        let result = unit_declarations(scanner, asm, name);
+
+        scanner.importing.pop();
+        scanner.tokens = tokens;
+        scanner.pos = pos;
+        scanner.cursor = cursor;
+        scanner.unit = unit;
+        scanner.dir = dir;
+        scanner.visible = visible;
+        result
+    }
+
+    fn unit_declarations(scanner: &mut Scanner, asm: &mut Vec<String>, name: &str) -> Result<(), String> {
+        imports(scanner, asm)?;
 
+        scanner.scope_name = format!("global.{name}");
+        scanner.emit(asm, format!("{name}:"));
+        scanner.nesting += 1;
+        declarations(scanner, asm)?;
+        scanner.nesting -= 1;
+        scanner.scope_name = "global".to_owned();
+
+        if !scanner.is_done() {
+            if !scanner.is_match(Token::Point) {
+                return Err(format!("Syntax error {:?}: a unit only has declarations, expected '.'", scanner.pos[scanner.cursor()]));
+            }
+            scanner.pop();
+            if !scanner.is_done() {
+                return Err("Tokens after '.' (POINT)".to_string());
+            }
+        }
         Ok(())
     }
 
     /* block = [ "const" ident "=" number {"," ident "=" number} ";"]
+        [ "type" ident "=" ( record | type ) ";" { ident "=" ( record | type ) ";" } ]
         [ "var" ident {"," ident} ";"]
-        { "forward" ident ";" }
-        { "procedure" ident ";" block ";" } statement ; */
+        { "forward" [ "function" ] ident ";" }
+        { ( "procedure" | "function" ) ident ";" block ";" } statement ; */
 
     fn block(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        declarations(scanner, asm)?;
+        statement(scanner, asm)
+    }
+
+    fn declarations(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
         scanner.local_constants = 0;
         if scanner.is_match(Token::Const) { 
             constant(scanner, asm)?; 
         }
+
+        if scanner.is_match(Token::Type) {
+            scanner.pop();
+            scanner.forward_types = true;
+            type_declaration(scanner)?;
+            while matches!(scanner.peek(), Some(Token::Ident{..})) {
+                type_declaration(scanner)?;
+            }
+            scanner.forward_types = false;
+            if let Some((_, name, pos)) = scanner.unresolved.first() {
+                return Err(format!("Error {:?}: unknown type {name}", pos));
+            }
+        }
         
         if scanner.is_match(Token::Var) {
             variable(scanner, asm)?;
@@ -413,36 +1204,45 @@ mod parser {
             forward(scanner)?;
         }
 
-        while scanner.is_match(Token::Procedure) {
+        while scanner.is_match(Token::Procedure) || scanner.is_match(Token::Function) || scanner.is_match(Token::Interrupt) {
             procedure(scanner, asm)?;
         }
-
-        statement(scanner, asm)?;
-
         Ok(())
     }
 
     fn constant_val(scanner: &mut Scanner) -> Result<i32, String> {
-        let tok = scanner.peek().clone();
+        let tok = scanner.peek();
         match tok {
             Some(&Token::Number{..}) => Ok(scanner.expect_num()?),
-            Some(&Token::Str(ref s)) => {
+            Some(Token::Str(s)) => {
                 let s = s.to_owned();
                 if s.len() == 1 {
                     scanner.pop();
-                    Ok(s.as_bytes()[0] as i32)
+                    Ok(s[0] as i32)
                 } else {
-                    return Err(format!("Constant Error {:?}: Constants can only take one character strings, got {s}", scanner.pos[scanner.cursor()]))
+                    Err(format!("Constant Error {:?}: Constants can only take one character strings, got '{}'", scanner.pos[scanner.cursor()], String::from_utf8_lossy(&s)))
                 }
             }
-            tok => return Err(format!("Constant Error {:?}: Constants can only be declared with, a number or a character, {:?}", scanner.pos[scanner.cursor()], tok))
+            Some(Token::Ident{..}) => {
+                let pos = scanner.pos[scanner.cursor()];
+                let id = scanner.expect_name()?;
+                let id = scanner.search_const(id)?;
+                scanner.constant_value(&id).ok_or(format!("Constant Error {:?}: {id} is not a constant", pos))
+            }
+            Some(Token::Minus) => {
+                scanner.pop();
+                Ok(constant_val(scanner)?.wrapping_neg())
+            }
+            tok => Err(format!("Constant Error {:?}: Constants can only be declared with, a number or a character, {:?}", scanner.pos[scanner.cursor()], tok))
         }
     }
 
     fn constant(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
         scanner.local_constants += 1;
         scanner.pop();
+        let pos = scanner.pos[scanner.cursor()];
         let id = scanner.expect_ident()?;
+        scanner.declarable(&format!("{}.{}", scanner.scope_name, id), pos)?;
         scanner.expect(&Token::Equals)?;
         let val = constant_val(scanner)?;
 
@@ -456,7 +1256,7 @@ mod parser {
         }*/
 
         scanner.emit(asm, format!("{n}{id} = {val}", n = ".".repeat(scanner.nesting)));
-        scanner.constants.push(format!("{}.{}", scanner.scope_name, id));
+        scanner.constants.push((format!("{}.{}", scanner.scope_name, id), val));
 
         if scanner.is_match(Token::Comma) {
             constant(scanner, asm)?;
@@ -470,30 +1270,52 @@ mod parser {
 
     fn variable(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
         scanner.pop();
+        let pos = scanner.pos[scanner.cursor()];
         let id = scanner.expect_ident()?;
         let qualified_id = format!("{}.{}", scanner.scope_name, id.clone());
+        scanner.declarable(&qualified_id, pos)?;
         scanner.scope.push(qualified_id.clone());
         let id = format!("{n}{id}", n = ".".repeat(scanner.nesting));
 
-        if scanner.is_match(Token::Size) {
+        let element = if scanner.is_match(Token::Colon) {
             scanner.pop();
-            match scanner.peek() {
-                Some(Token::Ident{..}) => {
-                let constant = scanner.expect_ident()?;
-                let size = scanner.search_const(constant)?.replace("global.", "");
-                scanner.emit(asm, format!("#[pragma(var)] {scope}; {id}: #res {size} * 4", scope = scanner.scope_name));
-                scanner.emit(asm, format!("#[pragma(var)] {scope}; {n}.len: #d32 {size}`32", scope = scanner.scope_name, n=".".repeat(scanner.nesting)));
-                }
-                _ => {
-                let size = scanner.expect_num()?;
-                if size < 1 { return Err(format!("Error defining array: {id} ({:?}): Array size must be greater than 0", scanner.pos))}
-                scanner.emit(asm, format!("#[pragma(var)] {scope}; {id}: #res {size} * 4 ", scope = scanner.scope_name));
-                scanner.emit(asm, format!("#[pragma(var)] {scope}; {n}.len: #d32 {size}`32", scope = scanner.scope_name, n=".".repeat(scanner.nesting)));
+            type_name(scanner)?
+        } else {
+            Type::Integer
+        };
+
+        let (sizes, dims) = dimensions(scanner, &id)?;
+
+        if !dims.is_empty() {
+            scanner.statics.push(Static { scope: scanner.scope_name.clone(), label: qualified_id.clone(), bytes: None, pos });
+            let array = Array { name: qualified_id, element, dims, lengths: true, open: false };
+            // char arrays are packed one byte per element, padded to keep the data section aligned
+            let storage = (array.elements() * element.size() + 3) / 4 * 4;
+            if scanner.is_match(Token::Equals) {
+                scanner.pop();
+                let init = initializer(scanner, &array)?;
+                let data = match element {
+                    Type::Char => format!("#d \"{}\"", asm_string(&init.iter().map(|&v| v as u8).collect::<Vec<_>>())),
+                    _ => format!("#d32 {}", init.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")),
+                };
+                scanner.emit(asm, format!("#[pragma(var)] {scope}; {id}: {data}", scope = scanner.scope_name));
+                let rest = storage - init.len() as i32 * element.size();
+                if rest > 0 {
+                    scanner.emit(asm, format!("#[pragma(var)] {scope}; #res {rest}", scope = scanner.scope_name));
                 }
+            } else {
+                scanner.emit(asm, format!("#[pragma(var)] {scope}; {id}: #res {storage}", scope = scanner.scope_name));
             }
-        scanner.arrays.push(qualified_id);
+            for (dim, size) in sizes.iter().enumerate() {
+                let len = match dim {
+                    0 => "len".to_string(),
+                    _ => format!("len{dim}")
+                };
+                scanner.emit(asm, format!("#[pragma(var)] {scope}; {n}.{len}: #d32 {size}`32", scope = scanner.scope_name, n=".".repeat(scanner.nesting)));
+            }
+            scanner.arrays.push(array);
         } else {
-            scanner.emit(asm, format!("#[pragma(var)] {scope}; {id}: #res 4", scope = scanner.scope_name));
+            scalar(scanner, asm, qualified_id, element);
         }
 
         if scanner.is_match(Token::Comma) {
@@ -507,44 +1329,388 @@ mod parser {
         Ok(())
     }
 
-    fn forward(scanner: &mut Scanner) -> Result<(), String> {
-        scanner.pop();
-        let id = scanner.expect_ident()?;
-        let qualified_id = format!("{}.{}", scanner.scope_name, id.clone());
-        scanner.scope.push(qualified_id);
+    /* dimensions = { "size" ( number | ident ) } ;
+       the sizes as written, for the data section, and their values */
+    fn dimensions(scanner: &mut Scanner, id: &str) -> Result<(Vec<String>, Vec<i32>), String> {
+        let mut sizes = vec![];
+        let mut dims = vec![];
+        while scanner.is_match(Token::Size) {
+            scanner.pop();
+            let (size, value) = match scanner.peek() {
+                Some(Token::Ident{..}) => {
+                    let constant = scanner.expect_name()?;
+                    let constant = scanner.search_const(constant)?;
+                    (constant.replace("global.", ""), scanner.constant_value(&constant))
+                }
+                _ => {
+                    let size = scanner.expect_num()?;
+                    (size.to_string(), Some(size))
+                }
+            };
+
+            match value {
+                Some(v) if v < 1 => return Err(format!("Error defining array: {id} ({:?}): Array size must be greater than 0", scanner.pos[scanner.cursor()])),
+                None => return Err(format!("Error defining array: {id} ({:?}): Array size must be a number or a constant", scanner.pos[scanner.cursor()])),
+                Some(v) => dims.push(v)
+            }
+            sizes.push(size);
+        }
+        Ok((sizes, dims))
+    }
+
+    /* type_declaration = ident "=" ( record | type ) ";" ;
+       pointers may name types declared later in the same section, so lists can be built */
+    fn type_declaration(scanner: &mut Scanner) -> Result<(), String> {
+        let name = scanner.expect_ident()?;
+        let qualified_id = format!("{}.{}", scanner.scope_name, name);
+        scanner.expect(&Token::Equals)?;
+        let t = if scanner.is_match(Token::Record) {
+            record(scanner, &name)?
+        } else {
+            type_name(scanner)?
+        };
         scanner.expect(&Token::Semic)?;
+        scanner.type_names.push((qualified_id, t));
+
+        let (resolved, unresolved) = scanner.unresolved.drain(..).partition(|(_, n, _)| *n == name);
+        scanner.unresolved = unresolved;
+        for (i, _, _) in resolved {
+            scanner.pointers[i] = Some(t);
+        }
         Ok(())
     }
 
-    fn procedure(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
-        scanner.pop();
-        let id = scanner.expect_ident()?;
-        let qualified_id = format!("{}.{}", scanner.scope_name, id.clone());
-        let old_scope = scanner.scope_name.clone();
-        scanner.scope_name = qualified_id.clone();
-        scanner.scope.push(scanner.scope_name.clone());
-        let id = format!("{n}{id}", n = ".".repeat(scanner.nesting));
-        scanner.emit(asm, format!("{id}:"));
-        scanner.nesting += 1;
-        scanner.expect(&Token::Semic)?;
+    /* record = "record" fields { ";" fields } [ ";" ] "end" ;
+       fields = ident { "," ident } ":" type dimensions ; */
+    fn record(scanner: &mut Scanner, name: &str) -> Result<Type, String> {
+        scanner.expect(&Token::Record)?;
 
-        block(scanner, asm)?;
+        let mut fields: Vec<Field> = vec![];
+        let mut offset = 0;
+        while !scanner.is_match(Token::End) {
+            let mut names = vec![(scanner.pos[scanner.cursor()], scanner.expect_ident()?)];
+            while scanner.is_match(Token::Comma) {
+                scanner.pop();
+                names.push((scanner.pos[scanner.cursor()], scanner.expect_ident()?));
+            }
+            scanner.expect(&Token::Colon)?;
+            let element = type_name(scanner)?;
+            let (_, dims) = dimensions(scanner, name)?;
+            let size = element.size() * dims.iter().product::<i32>();
+
+            for (pos, field) in names {
+                if fields.iter().any(|f| f.name == field) {
+                    return Err(format!("Error {:?}: {name} already has a field {field}", pos));
+                }
+                if element != Type::Char {
+                    offset = (offset + 3) / 4 * 4;
+                }
+                fields.push(Field { name: field, element, dims: dims.clone(), offset });
+                offset += size;
+            }
 
-        if scanner.cursor() + 1 == scanner.tokens.len() { 
-            scanner.expect(&Token::Point)?; 
-        } else {
-            scanner.expect(&Token::Semic)?;
+            if !scanner.is_match(Token::Semic) { break }
+            scanner.pop();
         }
+        scanner.expect(&Token::End)?;
 
-        //scanner.emit(asm, format!("pop ra, sp")); // Must be already in RA
-        scanner.emit(asm, format!("jalr zero, 0(ra)"));
-        scanner.nesting -= 1;
-        scanner.scope_drop(qualified_id);
-        scanner.scope_name = old_scope;
-        Ok(())
+        let size = ((offset + 3) / 4 * 4).max(4);
+        let t = Type::Record{id: scanner.records.len(), size};
+        scanner.records.push(Record { name: name.to_owned(), fields });
+        Ok(t)
     }
 
-    /*statement = [ ident ":=" expression | "call" ident 
+    // A variable in the data section of the current scope, a word unless it is a record
+    fn scalar(scanner: &mut Scanner, asm: &mut Vec<String>, qualified_id: String, t: Type) {
+        let id = qualified_id.rsplit('.').next().unwrap();
+        let size = match t {
+            Type::Record{size, ..} => size,
+            _ => 4
+        };
+        scanner.emit(asm, format!("#[pragma(var)] {scope}; {n}{id}: #res {size}", scope = scanner.scope_name, n = ".".repeat(scanner.nesting)));
+        scanner.statics.push(Static { scope: scanner.scope_name.clone(), label: qualified_id.clone(), bytes: Some(size), pos: scanner.pos[scanner.cursor() - 1] });
+        if t != Type::Integer {
+            scanner.types.push((qualified_id, t));
+        }
+    }
+
+    // An array parameter is the address of the argument, with its length beside it
+    fn open_array(scanner: &mut Scanner, asm: &mut Vec<String>, qualified_id: String, element: Type) {
+        let id = qualified_id.rsplit('.').next().unwrap();
+        let n = ".".repeat(scanner.nesting);
+        scanner.emit(asm, format!("#[pragma(var)] {scope}; {n}{id}: #res 4", scope = scanner.scope_name));
+        scanner.emit(asm, format!("#[pragma(var)] {scope}; {n}.len: #res 4", scope = scanner.scope_name));
+        let pos = scanner.pos[scanner.cursor() - 1];
+        scanner.statics.push(Static { scope: scanner.scope_name.clone(), label: qualified_id.clone(), bytes: Some(4), pos });
+        scanner.statics.push(Static { scope: scanner.scope_name.clone(), label: format!("{qualified_id}.len"), bytes: Some(4), pos });
+        scanner.arrays.push(Array { name: qualified_id, element, dims: vec![0], lengths: true, open: true });
+    }
+
+    /* initializer = "(" constant { "," constant } ")" | string ;
+       a string also stores its terminating NUL */
+    fn initializer(scanner: &mut Scanner, array: &Array) -> Result<Vec<i32>, String> {
+        let pos = scanner.pos[scanner.cursor()];
+        if let Type::Record{..} | Type::Pointer(_) = array.element {
+            return Err(format!("Error {:?}: arrays of {}s cannot be initialized", pos, &array.element.name()[2..]));
+        }
+        let values = match scanner.peek() {
+            Some(Token::Str(_)) if array.element == Type::Boolean => {
+                return Err(format!("Type error {:?}: a boolean array is initialized with a list of true and false", pos))
+            }
+            Some(Token::Str(s)) => {
+                let mut values: Vec<i32> = s.iter().map(|&b| b as i32).collect();
+                values.push(0);
+                scanner.pop();
+                values
+            }
+            _ => {
+                scanner.expect(&Token::LParen)?;
+                let mut values = vec![initial_value(scanner, array)?];
+                while scanner.is_match(Token::Comma) {
+                    scanner.pop();
+                    values.push(initial_value(scanner, array)?);
+                }
+                scanner.expect(&Token::RParen)?;
+                values
+            }
+        };
+
+        if values.len() as i32 > array.elements() {
+            return Err(format!("Error {:?}: initializer of {} elements does not fit in {} ({} elements)", pos, values.len(), array.name.replace("global.", ""), array.elements()));
+        }
+        if array.element == Type::Char {
+            if let Some(v) = values.iter().find(|&&v| !(0..=255).contains(&v)) {
+                return Err(format!("Error {:?}: {v} does not fit in an element of the char array {}", pos, array.name.replace("global.", "")));
+            }
+        }
+        Ok(values)
+    }
+
+    fn initial_value(scanner: &mut Scanner, array: &Array) -> Result<i32, String> {
+        match (array.element, scanner.peek()) {
+            (Type::Boolean, Some(Token::True)) | (Type::Boolean, Some(Token::False)) => Ok((scanner.pop() == Some(&Token::True)) as i32),
+            (Type::Boolean, _) => Err(format!("Type error {:?}: elements of the boolean array {} must be true or false", scanner.pos[scanner.cursor()], array.name.replace("global.", ""))),
+            _ => constant_val(scanner)
+        }
+    }
+
+    // type = [ "^" ] ( "integer" | "char" | "boolean" | ident ) . Type names are not reserved words
+    fn type_name(scanner: &mut Scanner) -> Result<Type, String> {
+        let pointer = scanner.is_match(Token::Caret);
+        if pointer {
+            scanner.pop();
+        }
+        let pos = scanner.pos[scanner.cursor()];
+        let name = scanner.expect_name()?;
+        let t = match name.to_lowercase().as_str() {
+            "integer" => Some(Type::Integer),
+            "char" => Some(Type::Char),
+            "boolean" => Some(Type::Boolean),
+            _ => scanner.search_type(&name)
+        };
+        match (pointer, t) {
+            (false, Some(t)) => Ok(t),
+            (true, None) if !scanner.forward_types => Err(format!("Error {:?}: unknown type {name}", pos)),
+            (true, t) => {
+                if t.is_none() {
+                    scanner.unresolved.push((scanner.pointers.len(), name, pos)); // an error unless declared later
+                }
+                scanner.pointers.push(t);
+                Ok(Type::Pointer(scanner.pointers.len() - 1))
+            }
+            (false, None) => Err(format!("Error {:?}: unknown type {name}", pos))
+        }
+    }
+
+    // Parameters and results are single words
+    fn scalar_type(scanner: &mut Scanner) -> Result<Type, String> {
+        let pos = scanner.pos[scanner.cursor()];
+        match type_name(scanner)? {
+            Type::Record{..} => Err(format!("Type error {:?}: parameters and results of functions cannot be records", pos)),
+            t => Ok(t)
+        }
+    }
+
+    fn forward(scanner: &mut Scanner) -> Result<(), String> {
+        scanner.pop();
+        let function = scanner.is_match(Token::Function);
+        if function {
+            scanner.pop();
+        }
+        let id = scanner.expect_ident()?;
+        let qualified_id = format!("{}.{}", scanner.scope_name, id.clone());
+        let sig = signature(scanner, &qualified_id, function)?;
+        scanner.signatures.push(sig);
+        scanner.scope.push(qualified_id);
+        scanner.expect(&Token::Semic)?;
+        Ok(())
+    }
+
+    /* signature = [ "(" params { ";" params } ")" ] [ ":" type ] ;
+       params = ident { "," ident } [ ":" ( type | "array" "of" type ) ] ; the result type is for functions only */
+    fn signature(scanner: &mut Scanner, id: &str, function: bool) -> Result<Signature, String> {
+        let mut params = vec![];
+        if scanner.is_match(Token::LParen) {
+            scanner.pop();
+            loop {
+                let mut names = vec![scanner.expect_ident()?];
+                while scanner.is_match(Token::Comma) {
+                    scanner.pop();
+                    names.push(scanner.expect_ident()?);
+                }
+                let (t, open) = if scanner.is_match(Token::Colon) {
+                    scanner.pop();
+                    if scanner.is_match(Token::Array) {
+                        scanner.pop();
+                        scanner.expect(&Token::Of)?;
+                        (type_name(scanner)?, true)
+                    } else {
+                        (scalar_type(scanner)?, false)
+                    }
+                } else {
+                    (Type::Integer, false)
+                };
+                params.extend(names.into_iter().map(|name| (format!("{id}.{name}"), t, open)));
+
+                if !scanner.is_match(Token::Semic) { break }
+                scanner.pop();
+            }
+            scanner.expect(&Token::RParen)?;
+        }
+
+        let result = match function {
+            true if scanner.is_match(Token::Colon) => {
+                scanner.pop();
+                Some(scalar_type(scanner)?)
+            }
+            true => Some(Type::Integer),
+            false => None
+        };
+        Ok(Signature { name: id.to_owned(), params, result })
+    }
+
+    /* handler = "interrupt" "procedure" ident "for" ident ;
+       the interrupt is named as in the IVT of sys.asm, without IVT_: KBD_CHARACTER */
+    fn handler(scanner: &mut Scanner, qualified_id: &str) -> Result<Signature, String> {
+        let id = qualified_id.replace("global.", "");
+        if !scanner.routines.is_empty() {
+            return Err(format!("Error {:?}: {id} is an interrupt handler, it must be declared outside of any procedure", scanner.pos[scanner.cursor() - 1]));
+        }
+        if scanner.is_match(Token::LParen) {
+            return Err(format!("Error {:?}: {id} is an interrupt handler, it takes no parameters", scanner.pos[scanner.cursor()]));
+        }
+        scanner.expect(&Token::For)?;
+        let pos = scanner.pos[scanner.cursor()];
+        let source = scanner.expect_ident()?;
+        let interrupts: Vec<String> = devices()?.iter()
+            .filter_map(|(name, _)| name.strip_prefix("global.IVT_").map(str::to_owned))
+            .filter(|name| name != "RESET")
+            .collect();
+        if !interrupts.contains(&source) {
+            return Err(format!("Error {:?}: {source} is not an interrupt, expected one of {}", pos, interrupts.join(", ")));
+        }
+        if let Some((other, _)) = scanner.interrupts.iter().find(|(_, s)| *s == source) {
+            return Err(format!("Error {:?}: {source} is already handled by {}", pos, other.replace("global.", "")));
+        }
+        scanner.interrupts.push((qualified_id.to_owned(), source));
+        Ok(Signature { name: qualified_id.to_owned(), params: vec![], result: None })
+    }
+
+    fn procedure(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let interrupt = scanner.is_match(Token::Interrupt);
+        if interrupt {
+            scanner.pop();
+            scanner.expect(&Token::Procedure)?;
+        }
+        let function = !interrupt && scanner.pop() == Some(&Token::Function);
+        let pos = scanner.pos[scanner.cursor()];
+        let id = scanner.expect_ident()?;
+        let qualified_id = format!("{}.{}", scanner.scope_name, id.clone());
+        scanner.declarable(&qualified_id, pos)?;
+        let sig = if interrupt {
+            handler(scanner, &qualified_id)?
+        } else {
+            signature(scanner, &qualified_id, function)?
+        };
+        if let Some(forward) = scanner.signature(&qualified_id) {
+            let types = |sig: &Signature| (sig.params.iter().map(|p| (p.1, p.2)).collect::<Vec<_>>(), sig.result);
+            if types(forward) != types(&sig) {
+                return Err(format!("Type error {:?}: {id} does not match its forward declaration", pos));
+            }
+        }
+        let result = sig.result;
+        let params = sig.params.clone();
+        scanner.signatures.push(sig);
+
+        let old_scope = scanner.scope_name.clone();
+        scanner.scope_name = qualified_id.clone();
+        scanner.scope.push(scanner.scope_name.clone());
+        let id = format!("{n}{id}", n = ".".repeat(scanner.nesting));
+        scanner.emit(asm, format!("{id}:"));
+        scanner.nesting += 1;
+        scanner.expect(&Token::Semic)?;
+
+        // A handler may stop the program anywhere, so it keeps every register the program uses
+        if interrupt {
+            let (_, source) = scanner.interrupts.last().unwrap();
+            asm.push(format!("#[pragma(interrupt)] IVT_{source} {}", qualified_id.replace("global.", "")));
+            for reg in INTERRUPT_SAVED {
+                scanner.emit(asm, format!("push {reg}, sp"));
+            }
+        }
+        if scanner.options.stack_check {
+            stack_check(scanner, asm, &qualified_id, pos);
+        }
+
+        for (param, t, open) in params {
+            scanner.scope.push(param.clone());
+            if open {
+                open_array(scanner, asm, param, t);
+            } else {
+                scalar(scanner, asm, param, t);
+            }
+        }
+
+        let epilogue = format!("{n}return", n = ".".repeat(scanner.nesting));
+        scanner.routines.push((epilogue.clone(), result));
+        block(scanner, asm)?;
+        scanner.routines.pop();
+
+        if scanner.cursor() + 1 == scanner.tokens.len() { 
+            scanner.expect(&Token::Point)?; 
+        } else {
+            scanner.expect(&Token::Semic)?;
+        }
+
+        if result.is_some() {
+            scanner.emit(asm, format!("mv {A}, zero")); // falling off the end returns 0
+        }
+        scanner.emit(asm, format!("{epilogue}:"));
+        //scanner.emit(asm, "pop ra, sp".to_string()); // Must be already in RA
+        if interrupt {
+            for reg in INTERRUPT_SAVED.iter().rev() {
+                scanner.emit(asm, format!("pop {reg}, sp"));
+            }
+            scanner.emit(asm, "sysret".to_string());
+        } else {
+            scanner.emit(asm, "jalr zero, 0(ra)".to_string());
+        }
+        scanner.nesting -= 1;
+        scanner.scope_drop(qualified_id);
+        scanner.scope_name = old_scope;
+        Ok(())
+    }
+
+    // Traps when the stack has grown below its limit. The runtime gets the name of the procedure in a0
+    fn stack_check(scanner: &Scanner, asm: &mut Vec<String>, qualified_id: &str, pos: (usize, usize)) {
+        let site = scanner.label("stack", pos);
+        scanner.emit(asm, format!("#[pragma(string_litteral)]{site}: #d \"{}\\0\"\n#align 32", qualified_id.replace("global.", "")));
+        scanner.emit(asm, format!("la a0, {site}"));
+        scanner.emit(asm, "la t1, global.stack_limit".to_string());
+        scanner.emit(asm, "bltu sp, t1, PL0_TRAP.stack".to_string());
+    }
+
+    /*statement = [ ident ":=" expression | "call" ident 
               | "?" ident | quaero ident | "!" expression | "echo" expression //TODO: corregir README
               | "begin" statement {";" statement } "end" 
               | "if" condition "then" statement 
@@ -553,6 +1719,8 @@ mod parser {
 
     fn statement(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
         match scanner.peek() {
+            Some(Token::Ident{val}) if ["pokeb", "pokew"].contains(&val.to_lowercase().as_str())
+                && scanner.tokens.get(scanner.cursor() + 1) == Some(&Token::LParen) && scanner.search(val.clone()).is_err() => poke(scanner, asm),
             Some(Token::Ident{..}) => assignement(scanner, asm),
             Some(Token::Call) => call(scanner, asm),
             Some(Token::Question) => input(scanner, asm),
@@ -562,47 +1730,378 @@ mod parser {
             Some(Token::Begin) => begin(scanner, asm),
             Some(Token::If) => if_statement(scanner, asm),
             Some(Token::While) => while_statement(scanner, asm),
+            Some(Token::For) => for_statement(scanner, asm),
+            Some(Token::Repeat) => repeat_statement(scanner, asm),
+            Some(Token::Do) => do_statement(scanner, asm),
+            Some(Token::Break) | Some(Token::Continue) => jump_statement(scanner, asm),
+            Some(Token::Case) => case_statement(scanner, asm),
             Some(Token::WriteStr) => output_string(scanner, asm),
             Some(Token::Exit) => exit_statement(scanner, asm),
-            _ => return Ok(())
+            Some(Token::Return) => return_statement(scanner, asm),
+            Some(Token::New) | Some(Token::Dispose) => memory_statement(scanner, asm),
+            Some(Token::Asm(_)) => asm_statement(scanner, asm),
+            _ => Ok(())
+        }
+    }
+
+    /* poke = ( "pokeb" | "pokew" ) "(" expression "," expression ")" ;
+       stores a byte or a word at an address of the devices. Neither is reserved */
+    fn poke(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let store = if scanner.expect_ident()?.to_lowercase() == "pokeb" { "sbd" } else { "swd" };
+        scanner.expect(&Token::LParen)?;
+        device_address(scanner, asm)?;
+        scanner.emit(asm, format!("push {A}, sp"));
+        scanner.expect(&Token::Comma)?;
+        let pos = scanner.pos[scanner.cursor()];
+        let t = expression(scanner, asm)?;
+        if !scanner.compatible(t, Type::Integer) {
+            return Err(format!("Type error {:?}: a device takes integers, got {}", pos, t.name()));
         }
+        scanner.expect(&Token::RParen)?;
+        scanner.emit(asm, format!("pop {B}, sp"));
+        scanner.emit(asm, format!("{store} {A}, 0({B})"));
+        Ok(())
+    }
+
+    // Leaves in A an address of the device space, such as K_CHARACTER
+    fn device_address(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        let t = expression(scanner, asm)?;
+        if !scanner.compatible(t, Type::Integer) {
+            return Err(format!("Type error {:?}: the address of a device is an integer, got {}", pos, t.name()));
+        }
+        Ok(())
     }
 
     fn assignement(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
         let id = scanner.expect_ident()?;
         let id = scanner.search(id)?;
 
-        if scanner.constants.contains(&id) {
-            return Err(format!("Error {:?}: Cannot assign value to constant", scanner.pos[scanner.cursor()]));
-        }
+        scanner.writable(&id)?;
 
-        if scanner.is_match(Token::LBrack) {
-            scanner.is_array(id.clone())?;
-            scanner.pop();
-            expression(scanner, asm)?;
-            scanner.expect(&Token::RBrack)?;
+        let place = designator(scanner, asm, &id)?;
+        let pos = scanner.pos[scanner.cursor()];
+        if place.is_value() {
             scanner.expect(&Token::CEquals)?;
-            scanner.emit(asm, format!("la {T}, {id}"));
-            scanner.emit(asm, format!("muli {A}, {A}, 4"));
-            scanner.emit(asm, format!("add {T}, {A}, {T}"));
-            expression(scanner, asm)?;
-            scanner.emit(asm, format!("sw {A}, 0({T})"));
-            
-        } else {
+            if place.memory {
+                scanner.emit(asm, format!("push {T}, sp"));
+                assigned_value(scanner, asm, &place.name, place.element)?;
+                scanner.emit(asm, format!("pop {T}, sp"));
+                scanner.emit(asm, format!("{store} {A}, 0({T})", store = place.element.store()));
+            } else {
+                assigned_value(scanner, asm, &place.name, place.element)?;
+                scanner.emit(asm, format!("ssw {A}, {id}, {T}"));
+            }
+        } else if place.array.is_some() && matches!(scanner.tokens.get(scanner.cursor() + 1), Some(Token::Str(..))) {
             scanner.expect(&Token::CEquals)?;
-            expression(scanner, asm)?;
-            scanner.emit(asm, format!("ssw {A}, {id}, {T}"));
+            string_assignement(scanner, asm, &place)?;
+        } else if place.array.is_some() {
+            return Err(format!("Type error {:?}: cannot assign to the array {} as a whole, only strings can be", pos, place.name));
+        } else {
+            return Err(format!("Type error {:?}: cannot assign to the record {} as a whole, assign its fields", pos, place.name));
         }
         Ok(())
     }
 
+    // The right hand side of an assignement, booleans only go into booleans
+    fn assigned_value(scanner: &mut Scanner, asm: &mut Vec<String>, id: &str, target: Type) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        let t = stored_expression(scanner, asm, target)?;
+        if !scanner.compatible(t, target) {
+            return Err(format!("Type error {:?}: cannot assign {} to {id}, which is {}", pos, t.name(), target.name()));
+        }
+        Ok(())
+    }
+
+    // Copies a string literal, with its terminating NUL, into an array. Integer
+    // arrays get one character per element
+    fn string_assignement(scanner: &mut Scanner, asm: &mut Vec<String>, place: &Place) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        let array = place.array.as_ref().unwrap();
+        let s = match scanner.pop() {
+            Some(Token::Str(s)) => s.clone(),
+            _ => unreachable!()
+        };
+
+        if !matches!(array.element, Type::Integer | Type::Char) {
+            return Err(format!("Type error {:?}: cannot assign a string to {}, an array of {}", pos, place.name, array.element.name()));
+        }
+        if array.open {
+            return Err(format!("Error {:?}: cannot assign a string to {}, the length of an array parameter is not known while compiling", pos, place.name));
+        }
+        if s.len() as i32 + 1 > array.elements() {
+            return Err(format!("Error {:?}: string of {} characters does not fit in {} ({} elements, counting the terminating NUL)", pos, s.len(), array.name, array.elements()));
+        }
+
+        let str_id = string_literal(scanner, asm, &s, pos);
+        scanner.emit(asm, "push ra, sp".to_string());
+        array_address(scanner, asm, place, "a0");
+        scanner.emit(asm, format!("la a1, {str_id}"));
+        match array.element {
+            Type::Integer => scanner.emit(asm, "jal ra, crt0.wstrcpy".to_string()),
+            Type::Char => scanner.emit(asm, "jal ra, crt0.strcpy".to_string()),
+            _ => unreachable!()
+        }
+        scanner.emit(asm, "pop ra, sp".to_string());
+        Ok(())
+    }
+
+    // Places a NUL terminated string literal in the data section and returns its label
+    fn string_literal(scanner: &Scanner, asm: &mut Vec<String>, s: &[u8], pos: (usize, usize)) -> String {
+        let str_id = scanner.label("str", pos);
+        scanner.emit(asm, format!("#[pragma(string_litteral)]{str_id}: #d \"{s}\\0\"\n#align 32", s = asm_string(s)));
+        str_id
+    }
+
+    /* designator = ident { index | "." ident | "^" } ;
+       elements of arrays and fields of records. Their address is left in T, plain
+       variables are accessed by their label */
+    fn designator(scanner: &mut Scanner, asm: &mut Vec<String>, id: &str) -> Result<Place, String> {
+        let mut place = Place { name: id.replace("global.", ""), element: scanner.type_of(id), array: scanner.array(id).ok(), memory: false };
+        loop {
+            let pos = scanner.pos[scanner.cursor()];
+            let field = scanner.is_match(Token::Point) && matches!(scanner.tokens.get(scanner.cursor() + 1), Some(Token::Ident{..}));
+            if scanner.is_match(Token::LBrack) {
+                let array = match place.array.take() {
+                    Some(array) => array,
+                    None => return Err(format!("Type error {:?}: {} is not an array", pos, place.name))
+                };
+                if place.memory {
+                    scanner.emit(asm, format!("push {T}, sp"));
+                    element_offset(scanner, asm, &array)?;
+                    scanner.emit(asm, format!("pop {T}, sp"));
+                    scanner.emit(asm, format!("add {T}, {A}, {T}"));
+                } else {
+                    element_address(scanner, asm, &array)?;
+                }
+                place.memory = true;
+            } else if scanner.is_match(Token::Caret) {
+                let target = match place.element {
+                    Type::Pointer(i) if place.is_value() => scanner.target(i, pos)?,
+                    _ => return Err(format!("Type error {:?}: {} is not a pointer", pos, place.name))
+                };
+                scanner.pop();
+                if place.memory {
+                    scanner.emit(asm, format!("lw {T}, 0({T})"));
+                } else {
+                    scanner.emit(asm, format!("llw {T}, {id}"));
+                }
+                scanner.emit(asm, format!("li a0, {}", pos.0)); // the runtime reports the source line
+                scanner.emit(asm, format!("beq {T}, zero, PL0_TRAP.nil"));
+                place = Place { name: format!("{}^", place.name), element: target, array: None, memory: true };
+            } else if field {
+                let record = match scanner.record(place.element) {
+                    Some(record) if place.array.is_none() => record.clone(),
+                    Some(_) => return Err(format!("Type error {:?}: {} is an array, index it to select a field", pos, place.name)),
+                    None => return Err(format!("Type error {:?}: {} is not a record", pos, place.name))
+                };
+                scanner.pop();
+                let name = scanner.expect_ident()?;
+                let field = match record.fields.iter().find(|f| f.name == name) {
+                    Some(field) => field,
+                    None => return Err(format!("Type error {:?}: record {} has no field {name}", pos, record.name))
+                };
+                if !place.memory {
+                    scanner.emit(asm, format!("la {T}, {id}"));
+                    place.memory = true;
+                }
+                if field.offset != 0 {
+                    scanner.emit(asm, format!("addi {T}, {T}, {}", field.offset));
+                }
+                place.name = format!("{}.{name}", place.name);
+                place.element = field.element;
+                if !field.dims.is_empty() {
+                    place.array = Some(Array { name: place.name.clone(), element: field.element, dims: field.dims.clone(), lengths: false, open: false });
+                }
+            } else {
+                return Ok(place);
+            }
+        }
+    }
+
+    // Moves the address of a designated array to a register
+    fn array_address(scanner: &Scanner, asm: &mut Vec<String>, place: &Place, reg: &str) {
+        match (&place.array, place.memory) {
+            (_, true) => scanner.emit(asm, format!("mv {reg}, {T}")),
+            (Some(array), false) if array.open => scanner.emit(asm, format!("llw {reg}, {}", array.name)),
+            (Some(array), false) => scanner.emit(asm, format!("la {reg}, {}", array.name)),
+            (None, false) => unreachable!()
+        }
+    }
+
+    // Leaves the address of an element of a named array in T
+    fn element_address(scanner: &mut Scanner, asm: &mut Vec<String>, array: &Array) -> Result<(), String> {
+        element_offset(scanner, asm, array)?;
+        if array.open {
+            scanner.emit(asm, format!("llw {T}, {}", array.name));
+        } else {
+            scanner.emit(asm, format!("la {T}, {}", array.name));
+        }
+        scanner.emit(asm, format!("add {T}, {A}, {T}"));
+        Ok(())
+    }
+
+    /* Leaves the offset of an element, in bytes, in A. Indices of multidimensional arrays
+       can be written `a[i, j]` or `a[i][j]`; the offset is accumulated row-major */
+    fn element_offset(scanner: &mut Scanner, asm: &mut Vec<String>, array: &Array) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        scanner.expect(&Token::LBrack)?;
+        integer_expression(scanner, asm)?;
+        if scanner.options.bounds_check {
+            bounds_check(scanner, asm, array, 0, pos);
+        }
+
+        for (dim, &size) in array.dims.iter().enumerate().skip(1) {
+            let pos = scanner.pos[scanner.cursor()];
+            if scanner.is_match(Token::RBrack) && scanner.tokens.get(scanner.cursor() + 1) == Some(&Token::LBrack) {
+                scanner.pop();
+                scanner.pop();
+            } else if scanner.is_match(Token::Comma) {
+                scanner.pop();
+            } else {
+                return Err(format!("Error {:?}: {} has {} dimensions, but only {dim} indices were given", pos, array.name, array.dims.len()));
+            }
+            scanner.emit(asm, format!("push {A}, sp"));
+            integer_expression(scanner, asm)?;
+            if scanner.options.bounds_check {
+                bounds_check(scanner, asm, array, dim, pos);
+            }
+            scanner.emit(asm, format!("pop {B}, sp"));
+            if size < 0x4000 {
+                scanner.emit(asm, format!("muli {B}, {B}, {size}"));
+            } else {
+                scanner.emit(asm, format!("li {T}, {size}"));
+                scanner.emit(asm, format!("mul zero, {B}, {B}, {T}"));
+            }
+            scanner.emit(asm, format!("add {A}, {A}, {B}"));
+        }
+        scanner.expect(&Token::RBrack)?;
+
+        if array.element.size() > 1 {
+            scanner.emit(asm, format!("muli {A}, {A}, {}", array.element.size()));
+        }
+        Ok(())
+    }
+
+    // Traps unless 0 <= A < the length of the dimension. The runtime gets the index
+    // in a1 and, in a0, a descriptor of the access: the source line followed by the array name
+    fn bounds_check(scanner: &Scanner, asm: &mut Vec<String>, array: &Array, dim: usize, pos: (usize, usize)) {
+        let site = scanner.label("bounds", pos);
+        let name = array.name.replace("global.", "");
+        scanner.emit(asm, format!("#[pragma(string_litteral)]{site}: #d32 {line}\n#d \"{name}\\0\"\n#align 32", line = pos.0));
+        scanner.emit(asm, format!("la a0, {site}"));
+        scanner.emit(asm, format!("mv a1, {A}"));
+        if array.lengths {
+            scanner.emit(asm, format!("llw {B}, {}", array.len_label(dim)));
+        } else {
+            scanner.emit(asm, format!("li {B}, {}", array.dims[dim]));
+        }
+        scanner.emit(asm, format!("bgeu {A}, {B}, PL0_TRAP.bounds"));
+    }
+
     fn call(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
         scanner.pop();
+        let id = scanner.expect_name()?;
+        let id = scanner.search(id)?;
+        let sig = match scanner.signature(&id) {
+            Some(sig) => sig.clone(),
+            None => return Err(format!("Type error {:?}: {} is a variable, only procedures can be called", scanner.pos[scanner.cursor() - 1], id.replace("global.", "")))
+        };
+        if scanner.interrupts.iter().any(|(name, _)| *name == id) {
+            return Err(format!("Type error {:?}: {} is an interrupt handler, it cannot be called", scanner.pos[scanner.cursor() - 1], id.replace("global.", "")));
+        }
+        call_routine(scanner, asm, &sig)
+    }
+
+    /* arguments = [ "(" expression { "," expression } ")" ] ;
+       The arguments are evaluated onto the stack first, so calls among them
+       do not overwrite the parameters of this one. The last one is still in A */
+    fn call_routine(scanner: &mut Scanner, asm: &mut Vec<String>, sig: &Signature) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor() - 1];
+        let name = sig.name.replace("global.", "");
+        scanner.calls.push((scanner.scope_name.clone(), sig.name.clone()));
+        scanner.emit(asm, format!("#[pragma(save)] {}", sig.name)); // see frames()
+        let mut args = 0;
+        if scanner.is_match(Token::LParen) {
+            scanner.pop();
+            while !scanner.is_match(Token::RParen) {
+                if args > 0 {
+                    scanner.expect(&Token::Comma)?;
+                    scanner.emit(asm, format!("push {A}, sp"));
+                }
+                let pos = scanner.pos[scanner.cursor()];
+                match sig.params.get(args) {
+                    Some(&(ref param, element, true)) => array_argument(scanner, asm, &name, param, element)?,
+                    param => {
+                        let t = stored_expression(scanner, asm, param.map_or(Type::Integer, |p| p.1))?;
+                        if let Some(&(ref param, expected, _)) = param {
+                            if !scanner.compatible(t, expected) {
+                                return Err(format!("Type error {:?}: argument {} of {name} must be {}, got {}", pos, param.rsplit('.').next().unwrap(), expected.name(), t.name()));
+                            }
+                        }
+                    }
+                }
+                args += 1;
+            }
+            scanner.pop();
+        }
+        if args != sig.params.len() {
+            return Err(format!("Type error {:?}: {} {name} takes {} arguments, got {args}", pos, sig.kind(), sig.params.len()));
+        }
+
+        for (i, (param, _, open)) in sig.params.iter().enumerate().rev() {
+            if i + 1 < args {
+                scanner.emit(asm, format!("pop {A}, sp"));
+            }
+            scanner.emit(asm, format!("ssw {A}, {param}, {T}"));
+            if *open {
+                scanner.emit(asm, format!("pop {A}, sp"));
+                scanner.emit(asm, format!("ssw {A}, {param}.len, {T}"));
+            }
+        }
+        scanner.emit(asm, "push ra, sp".to_string());
+        scanner.emit(asm, format!("jal ra, {name}"));
+        scanner.emit(asm, "pop ra, sp".to_string());
+        scanner.emit(asm, format!("#[pragma(restore)] {}", sig.name));
+        Ok(())
+    }
+
+    // Pushes the length of a one dimensional array and leaves its address in A.
+    // Arrays of chars can also be given a string
+    fn array_argument(scanner: &mut Scanner, asm: &mut Vec<String>, routine: &str, param: &str, element: Type) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        let param = param.rsplit('.').next().unwrap();
+        let expected = format!("argument {param} of {routine} must be an array of {}", element.name());
+        if let Some(Token::Str(s)) = scanner.peek() {
+            if element != Type::Char {
+                return Err(format!("Type error {:?}: {expected}, got a string", pos));
+            }
+            let s = s.clone();
+            scanner.pop();
+            let str_id = string_literal(scanner, asm, &s, pos);
+            scanner.emit(asm, format!("li {A}, {}", s.len() + 1));
+            scanner.emit(asm, format!("push {A}, sp"));
+            scanner.emit(asm, format!("la {A}, {str_id}"));
+            return Ok(());
+        }
+
         let id = scanner.expect_ident()?;
-        let id = scanner.search(id)?.replace("global.", "");
-        scanner.emit(asm, format!("push ra, sp"));
-        scanner.emit(asm, format!("jal ra, {id}"));
-        scanner.emit(asm, format!("pop ra, sp"));
+        let id = scanner.search(id)?;
+        let place = designator(scanner, asm, &id)?;
+        let array = match &place.array {
+            Some(array) if array.dims.len() == 1 && (array.element == element
+                || array.element.size() == element.size() && scanner.compatible(array.element, element)) => array,
+            Some(array) if array.dims.len() > 1 => return Err(format!("Type error {:?}: {expected}, {} has {} dimensions", pos, place.name, array.dims.len())),
+            Some(array) => return Err(format!("Type error {:?}: {expected}, {} is an array of {}", pos, place.name, array.element.name())),
+            None => return Err(format!("Type error {:?}: {expected}, {} is not an array", pos, place.name))
+        };
+        if array.open {
+            scanner.emit(asm, format!("llw {A}, {}", array.len_label(0)));
+        } else {
+            scanner.emit(asm, format!("li {A}, {}", array.dims[0]));
+        }
+        scanner.emit(asm, format!("push {A}, sp"));
+        array_address(scanner, asm, &place, A);
         Ok(())
     }
 
@@ -611,39 +2110,64 @@ mod parser {
         if scanner.is_match(Token::Into) {scanner.pop();}
         let id = scanner.expect_ident()?;
         let id = scanner.search(id)?;
-        scanner.emit(asm, format!("push ra, sp"));
-        scanner.emit(asm, format!("jal ra, PL0_INPUT.int"));
-        scanner.emit(asm, format!("pop ra, sp"));
+        scanner.writable(&id)?;
+        readable(scanner, &id)?;
+        scanner.emit(asm, "push ra, sp".to_string());
+        scanner.emit(asm, "jal ra, PL0_INPUT.int".to_string());
+        scanner.emit(asm, "pop ra, sp".to_string());
         scanner.emit(asm, format!("mv {A}, a0"));
         scanner.emit(asm, format!("ssw {A}, {id}, {T}"));
         Ok(())
     }
 
+    // Input is numbers and characters
+    fn readable(scanner: &Scanner, id: &str) -> Result<(), String> {
+        if scanner.array(id).is_ok() {
+            return Err(format!("Type error {:?}: cannot read into the array {} as a whole", scanner.pos[scanner.cursor() - 1], id.replace("global.", "")));
+        }
+        match scanner.type_of(id) {
+            Type::Integer | Type::Char => Ok(()),
+            t => Err(format!("Type error {:?}: cannot read into {}, which is {}", scanner.pos[scanner.cursor() - 1], id.replace("global.", ""), t.name()))
+        }
+    }
+
     fn output(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
         scanner.pop();
         expression(scanner, asm)?;
         scanner.emit(asm, format!("mv a0, {A}"));
-        scanner.emit(asm, format!("push ra, sp"));
-        scanner.emit(asm, format!("jal ra, PL0_OUTPUT"));
-        scanner.emit(asm, format!("pop ra, sp"));
+        scanner.emit(asm, "push ra, sp".to_string());
+        scanner.emit(asm, "jal ra, PL0_OUTPUT".to_string());
+        scanner.emit(asm, "pop ra, sp".to_string());
         Ok(())
     }
 
     fn output_char(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
         scanner.pop();
-        expression(scanner, asm)?;
+        integer_expression(scanner, asm)?;
         scanner.emit(asm, format!("sbd {A}, T_TX(zero)"));
         Ok(())
     }
 
-    fn output_string(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> { //TODO: make strigns be of 8 bits instead of 32
+    fn output_string(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
         scanner.pop();
         match scanner.peek() {
             Some(&Token::Ident{..}) => { // Print until \0 even if array is larger
                 let id = scanner.expect_ident()?;
                 let id = scanner.search(id)?;
-                scanner.is_array(id.clone())?;
-                scanner.emit(asm, format!("la {A}, {id}"));
+                let pos = scanner.pos[scanner.cursor()];
+                let place = designator(scanner, asm, &id)?;
+                let element = match &place.array {
+                    Some(array) if matches!(array.element, Type::Integer | Type::Char) => array.element,
+                    _ => return Err(format!("Type error {:?}: {} is not an array of characters", pos, place.name))
+                };
+                if element == Type::Char {
+                    scanner.emit(asm, "push ra, sp".to_string());
+                    array_address(scanner, asm, &place, "a0");
+                    scanner.emit(asm, "jal ra, crt0.puts".to_string());
+                    scanner.emit(asm, "pop ra, sp".to_string());
+                    return Ok(());
+                }
+                array_address(scanner, asm, &place, A);
                 scanner.emit(asm, format!("{n}writeStr_loop:", n=".".repeat(scanner.nesting)));
                 scanner.emit(asm, format!("lw {T}, 0({A})"));
                 scanner.emit(asm, format!("beq {T}, zero, {n}writeStr_exit", n=".".repeat(scanner.nesting)));
@@ -652,15 +2176,15 @@ mod parser {
                 scanner.emit(asm, format!("j {n}writeStr_loop", n=".".repeat(scanner.nesting)));
                 scanner.emit(asm, format!("{n}writeStr_exit:", n=".".repeat(scanner.nesting)));
             }
-            Some(&Token::Str(ref s)) => {
+            Some(Token::Str(s)) => {
                 let s = s.clone();
+                let pos = scanner.pos[scanner.cursor()];
                 scanner.pop();
-                let str_id = format!("str_{}_{}", scanner.pos[scanner.cursor()].0, scanner.pos[scanner.cursor()].1);
-                scanner.emit(asm, format!("#[pragma(string_litteral)]{str_id}: #d \"{s}\\0\"\n#align 32"));
-                scanner.emit(asm, format!("push ra, sp"));
+                let str_id = string_literal(scanner, asm, &s, pos);
+                scanner.emit(asm, "push ra, sp".to_string());
                 scanner.emit(asm, format!("la a0, {str_id}"));
-                scanner.emit(asm, format!("jal ra, crt0.puts"));
-                scanner.emit(asm, format!("pop ra, sp"));
+                scanner.emit(asm, "jal ra, crt0.puts".to_string());
+                scanner.emit(asm, "pop ra, sp".to_string());
             }
             _ => return Err(format!("Syntax Error ({:?}): WriteStr takes either a string or an array", scanner.pos[scanner.cursor()])),
         }
@@ -672,9 +2196,11 @@ mod parser {
         if scanner.is_match(Token::Into) { scanner.pop(); }
         let id = scanner.expect_ident()?;
         let id = scanner.search(id)?;
-        scanner.emit(asm, format!("push ra, sp"));
-        scanner.emit(asm, format!("jal ra, PL0_INPUT.char"));
-        scanner.emit(asm, format!("pop ra, sp"));
+        scanner.writable(&id)?;
+        readable(scanner, &id)?;
+        scanner.emit(asm, "push ra, sp".to_string());
+        scanner.emit(asm, "jal ra, PL0_INPUT.char".to_string());
+        scanner.emit(asm, "pop ra, sp".to_string());
         scanner.emit(asm, format!("mv {A}, a0"));
         scanner.emit(asm, format!("ssw {A}, {id}, {T}"));
         Ok(())
@@ -698,56 +2224,459 @@ mod parser {
     }
 
     fn if_statement(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        let label = format!("_{}_{}", pos.0, pos.1); // sibling ifs must not share labels
         scanner.pop();
-        scanner.emit(asm, format!("{n}if:", n = ".".repeat(scanner.nesting)));
+        scanner.emit(asm, format!("{n}if{label}:", n = ".".repeat(scanner.nesting)));
         scanner.nesting += 1;
         condition(scanner, asm)?;
-        scanner.emit(asm, format!("beq {A}, zero, {n}else", n = ".".repeat(scanner.nesting - 1)));
+        scanner.emit(asm, format!("beq {A}, zero, {n}else{label}", n = ".".repeat(scanner.nesting - 1)));
         scanner.expect(&Token::Then)?;
         statement(scanner, asm)?;
         scanner.nesting -= 1;
-        scanner.emit(asm, format!("j {n}exit", n = ".".repeat(scanner.nesting)));
-        scanner.emit(asm, format!("{n}else:", n = ".".repeat(scanner.nesting)));
+        scanner.emit(asm, format!("j {n}exit{label}", n = ".".repeat(scanner.nesting)));
+        scanner.emit(asm, format!("{n}else{label}:", n = ".".repeat(scanner.nesting)));
         scanner.nesting += 1;
         if scanner.is_match(Token::Else) { 
             scanner.pop();
             statement(scanner, asm)? 
         }
         scanner.nesting -= 1;
-        scanner.emit(asm, format!("{n}exit:", n = ".".repeat(scanner.nesting)));
+        scanner.emit(asm, format!("{n}exit{label}:", n = ".".repeat(scanner.nesting)));
 
         Ok(())
     }
 
     fn while_statement(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        let label = format!("_{}_{}", pos.0, pos.1);
         scanner.pop();
-        scanner.emit(asm, format!("{n}while:", n = ".".repeat(scanner.nesting)));
+        scanner.emit(asm, format!("{n}while{label}:", n = ".".repeat(scanner.nesting)));
         scanner.nesting += 1;
 
         condition(scanner, asm)?;
 
-        scanner.emit(asm, format!("beq {A}, zero, {n}end_while", n = ".".repeat(scanner.nesting - 1)));
+        let n = ".".repeat(scanner.nesting - 1);
+        scanner.emit(asm, format!("beq {A}, zero, {n}end_while{label}"));
+
+        scanner.expect(&Token::Do)?;
+        scanner.loops.push((format!("{n}while{label}"), format!("{n}end_while{label}")));
+        statement(scanner, asm)?;
+        scanner.loops.pop();
+
+        scanner.nesting -= 1;
+        scanner.emit(asm, format!("j {n}while{label}"));
+        scanner.emit(asm, format!("{n}end_while{label}:"));
+        Ok(())
+    }
+
+    // break = "break" ; continue = "continue" ; only inside loops
+    fn jump_statement(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        let tok = scanner.pop().cloned();
+        let (next, exit) = match scanner.loops.last() {
+            Some(labels) => labels.clone(),
+            None => return Err(format!("Error {:?}: {:?} outside of a loop", pos, tok.unwrap()))
+        };
+        match tok {
+            Some(Token::Break) => scanner.emit(asm, format!("j {exit}")),
+            _ => scanner.emit(asm, format!("j {next}"))
+        }
+        Ok(())
+    }
+
+    // repeat = "repeat" statement { ";" statement } "until" condition ;
+    fn repeat_statement(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        let label = format!("_{}_{}", pos.0, pos.1);
+        scanner.pop();
+        let n = ".".repeat(scanner.nesting);
+        scanner.emit(asm, format!("{n}repeat{label}:"));
+        scanner.nesting += 1;
+
+        scanner.loops.push((format!("{n}until{label}"), format!("{n}end_repeat{label}")));
+        statement(scanner, asm)?;
+        while scanner.is_match(Token::Semic) {
+            scanner.pop();
+            statement(scanner, asm)?;
+        }
+        scanner.loops.pop();
+
+        scanner.expect(&Token::Until)?;
+        scanner.nesting -= 1;
+        scanner.emit(asm, format!("{n}until{label}:"));
+        scanner.nesting += 1;
+        condition(scanner, asm)?;
+        scanner.emit(asm, format!("beq {A}, zero, {n}repeat{label}"));
+        scanner.nesting -= 1;
+        scanner.emit(asm, format!("{n}end_repeat{label}:"));
+        Ok(())
+    }
+
+    // do = "do" statement "while" condition ;
+    fn do_statement(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        let label = format!("_{}_{}", pos.0, pos.1);
+        scanner.pop();
+        let n = ".".repeat(scanner.nesting);
+        scanner.emit(asm, format!("{n}do{label}:"));
+        scanner.nesting += 1;
+
+        scanner.loops.push((format!("{n}do_while{label}"), format!("{n}end_do{label}")));
+        statement(scanner, asm)?;
+        scanner.loops.pop();
+
+        scanner.expect(&Token::While)?;
+        scanner.nesting -= 1;
+        scanner.emit(asm, format!("{n}do_while{label}:"));
+        scanner.nesting += 1;
+        condition(scanner, asm)?;
+        scanner.emit(asm, format!("bne {A}, zero, {n}do{label}"));
+        scanner.nesting -= 1;
+        scanner.emit(asm, format!("{n}end_do{label}:"));
+        Ok(())
+    }
+
+    /* for = "for" ident ":=" expression ( "to" | "downto" ) expression [ "by" number ] "do" statement ;
+       the bound is evaluated once, the step must be a positive constant and
+       the loop variable cannot be assigned in the body */
+    fn for_statement(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        scanner.pop();
+        let id = scanner.expect_ident()?;
+        let id = scanner.search(id)?;
+        scanner.writable(&id)?;
+        if scanner.array(&id).is_ok() {
+            return Err(format!("Error {:?}: the variable of a for loop cannot be an array", pos));
+        }
+        if !matches!(scanner.type_of(&id), Type::Integer | Type::Char) {
+            return Err(format!("Type error {:?}: the variable of a for loop cannot be {}", pos, scanner.type_of(&id).name()));
+        }
+
+        scanner.expect(&Token::CEquals)?;
+        let variable = scanner.type_of(&id);
+        stored_expression(scanner, asm, variable)?;
+        scanner.emit(asm, format!("ssw {A}, {id}, {T}"));
+
+        let down = match scanner.pop() {
+            Some(Token::To) => false,
+            Some(Token::Downto) => true,
+            tok => return Err(format!("Syntax error {:?}: expected 'to' or 'downto', got {:?}", pos, tok))
+        };
+        stored_expression(scanner, asm, variable)?;
+        let label = format!("_{}_{}", pos.0, pos.1); // sibling loops must not share labels
+        let bound = scanner.hidden_variable(asm, format!("for{label}"));
+        scanner.emit(asm, format!("ssw {A}, {bound}, {T}"));
+
+        let step = if scanner.is_match(Token::By) {
+            scanner.pop();
+            let step = constant_val(scanner)?;
+            if step < 1 {
+                return Err(format!("Error {:?}: the step of a for loop must be a positive constant, use 'downto' to count down", pos));
+            }
+            step
+        } else {
+            1
+        };
+        let step = if down { -step } else { step };
+
+        scanner.emit(asm, format!("{n}for{l}:", n = ".".repeat(scanner.nesting), l = label));
+        scanner.nesting += 1;
+        scanner.emit(asm, format!("llw {A}, {id}"));
+        scanner.emit(asm, format!("llw {B}, {bound}"));
+        if down {
+            scanner.emit(asm, format!("blt {A}, {B}, {n}end_for{l}", n = ".".repeat(scanner.nesting - 1), l = label));
+        } else {
+            scanner.emit(asm, format!("blt {B}, {A}, {n}end_for{l}", n = ".".repeat(scanner.nesting - 1), l = label));
+        }
+
+        scanner.expect(&Token::Do)?;
+        let n = ".".repeat(scanner.nesting - 1);
+        scanner.loops.push((format!("{n}for_next{label}"), format!("{n}end_for{label}")));
+        scanner.loop_variables.push(id.clone());
+        statement(scanner, asm)?;
+        scanner.loop_variables.pop();
+        scanner.loops.pop();
+
+        scanner.nesting -= 1;
+        scanner.emit(asm, format!("{n}for_next{l}:", n = ".".repeat(scanner.nesting), l = label));
+        scanner.nesting += 1;
+        scanner.emit(asm, format!("llw {A}, {id}"));
+        if (-0x4000..0x4000).contains(&step) {
+            scanner.emit(asm, format!("addi {A}, {A}, {step}"));
+        } else {
+            scanner.emit(asm, format!("li {B}, {step}"));
+            scanner.emit(asm, format!("add {A}, {A}, {B}"));
+        }
+        scanner.emit(asm, format!("ssw {A}, {id}, {T}"));
+        scanner.nesting -= 1;
+        scanner.emit(asm, format!("j {n}for{l}", n = ".".repeat(scanner.nesting), l = label));
+        scanner.emit(asm, format!("{n}end_for{l}:", n = ".".repeat(scanner.nesting), l = label));
+        Ok(())
+    }
+
+    /* case = "case" expression "of" arm { ";" arm } [ ";" ] [ "else" statement { ";" statement } ] "end" ;
+       arm = label { "," label } ":" statement ; label = constant [ ".." constant ] ;
+       The arms are compiled to a side buffer, since the dispatch can only be
+       chosen once every label is known: a jump table if the labels are dense,
+       otherwise a chain of comparisons */
+    fn case_statement(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        let label = format!("_{}_{}", pos.0, pos.1);
+        scanner.pop();
+        integer_expression(scanner, asm)?;
+        scanner.expect(&Token::Of)?;
+
+        let n = ".".repeat(scanner.nesting);
+        let mut ranges: Vec<(i32, i32, usize)> = vec![];
+        let mut arms = vec![];
+        let mut arm = 0;
+        while !scanner.is_match(Token::Else) && !scanner.is_match(Token::End) {
+            loop {
+                let pos = scanner.pos[scanner.cursor()];
+                let lo = constant_val(scanner)?;
+                let hi = if scanner.is_match(Token::DotDot) {
+                    scanner.pop();
+                    constant_val(scanner)?
+                } else {
+                    lo
+                };
+                if hi < lo {
+                    return Err(format!("Error {:?}: empty case range {lo}..{hi}", pos));
+                }
+                if let Some(&(l, h, _)) = ranges.iter().find(|&&(l, h, _)| lo <= h && l <= hi) {
+                    let used = if l == h { format!("{l}") } else { format!("{l}..{h}") };
+                    return Err(format!("Error {:?}: duplicate case label, {} overlaps {used}", pos, lo.max(l)));
+                }
+                ranges.push((lo, hi, arm));
+
+                if !scanner.is_match(Token::Comma) { break }
+                scanner.pop();
+            }
+            scanner.expect(&Token::Colon)?;
+
+            scanner.emit(&mut arms, format!("{n}of{label}_{arm}:"));
+            scanner.nesting += 1;
+            statement(scanner, &mut arms)?;
+            scanner.emit(&mut arms, format!("j {n}end_case{label}"));
+            scanner.nesting -= 1;
+            arm += 1;
+
+            if !scanner.is_match(Token::Semic) { break }
+            scanner.pop();
+        }
+        if ranges.is_empty() {
+            return Err(format!("Syntax error {:?}: a case statement needs at least one label", pos));
+        }
+
+        scanner.emit(&mut arms, format!("{n}case_else{label}:"));
+        if scanner.is_match(Token::Else) {
+            scanner.pop();
+            scanner.nesting += 1;
+            statement(scanner, &mut arms)?;
+            while scanner.is_match(Token::Semic) {
+                scanner.pop();
+                statement(scanner, &mut arms)?;
+            }
+            scanner.nesting -= 1;
+        }
+        scanner.expect(&Token::End)?;
+        scanner.emit(&mut arms, format!("{n}end_case{label}:"));
+
+        scanner.emit(asm, format!("{n}case{label}:"));
+        scanner.nesting += 1;
+        let min = ranges.iter().map(|r| r.0).min().unwrap();
+        let max = ranges.iter().map(|r| r.1).max().unwrap();
+        let span = max as i64 - min as i64 + 1;
+        let covered: i64 = ranges.iter().map(|&(lo, hi, _)| hi as i64 - lo as i64 + 1).sum();
+        if ranges.len() >= 3 && span <= 256 && span <= 2 * covered {
+            if min != 0 {
+                scanner.emit(asm, format!("li {B}, {min}"));
+                scanner.emit(asm, format!("sub {A}, {A}, {B}"));
+            }
+            scanner.emit(asm, format!("li {B}, {span}"));
+            scanner.emit(asm, format!("bgeu {A}, {B}, {n}case_else{label}"));
+            scanner.emit(asm, format!("muli {A}, {A}, 4"));
+            scanner.emit(asm, format!("la {T}, {n}case_table{label}"));
+            scanner.emit(asm, format!("add {T}, {A}, {T}"));
+            scanner.emit(asm, format!("lw {T}, 0({T})"));
+            scanner.emit(asm, format!("jalr zero, 0({T})"));
+
+            let table: Vec<String> = (min as i64..=max as i64).map(|v| {
+                match ranges.iter().find(|&&(lo, hi, _)| lo as i64 <= v && v <= hi as i64) {
+                    Some(&(_, _, arm)) => format!("{n}of{label}_{arm}"),
+                    None => format!("{n}case_else{label}")
+                }
+            }).collect();
+            scanner.nesting -= 1;
+            scanner.emit(asm, format!("{n}case_table{label}:"));
+            scanner.nesting += 1;
+            for row in table.chunks(4) {
+                scanner.emit(asm, format!("#d32 {}", row.join(", ")));
+            }
+        } else {
+            for &(lo, hi, arm) in &ranges {
+                scanner.emit(asm, format!("li {B}, {lo}"));
+                if lo == hi {
+                    scanner.emit(asm, format!("beq {A}, {B}, {n}of{label}_{arm}"));
+                } else {
+                    scanner.emit(asm, format!("sub {T}, {A}, {B}"));
+                    scanner.emit(asm, format!("li {B}, {}", hi.wrapping_sub(lo)));
+                    scanner.emit(asm, format!("bgeu {B}, {T}, {n}of{label}_{arm}"));
+                }
+            }
+            scanner.emit(asm, format!("j {n}case_else{label}"));
+        }
+        scanner.nesting -= 1;
+        asm.extend(arms);
+        Ok(())
+    }
+
+    /* return = "return" [ expression ] ;
+       Functions leave their result in A. Nothing is kept on the stack between
+       statements, so the epilogue only has to jump back through ra */
+    fn return_statement(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        scanner.pop();
+        let (epilogue, result) = match scanner.routines.last() {
+            Some(routine) => routine.clone(),
+            None => return Err(format!("Error {:?}: return outside of a procedure", pos))
+        };
+        let value = !matches!(scanner.peek(), None | Some(Token::Semic) | Some(Token::End) | Some(Token::Else) | Some(Token::Until) | Some(Token::While));
+        match (result, value) {
+            (Some(result), true) => {
+                let pos = scanner.pos[scanner.cursor()];
+                let t = stored_expression(scanner, asm, result)?;
+                if !scanner.compatible(t, result) {
+                    return Err(format!("Type error {:?}: {} returns {}, got {}", pos, scanner.scope_name.replace("global.", ""), result.name(), t.name()));
+                }
+            }
+            (Some(_), false) => return Err(format!("Error {:?}: {} is a function and must return a value", pos, scanner.scope_name.replace("global.", ""))),
+            (None, true) => return Err(format!("Error {:?}: {} is a procedure and cannot return a value", pos, scanner.scope_name.replace("global.", ""))),
+            (None, false) => ()
+        }
+        scanner.emit(asm, format!("j {epilogue}"));
+        Ok(())
+    }
+
+    /* asm = "asm" text "end" [ "[" register { "," register } "]" ] ;
+       The text is copied as it is, but for {name}, which becomes the label or the
+       constant name refers to, and {.}, the dots of a label local to the block.
+       The registers listed are saved around the block */
+    fn asm_statement(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        let text = match scanner.pop() {
+            Some(Token::Asm(text)) => text.clone(),
+            _ => unreachable!()
+        };
+        let mut saved = vec![];
+        if scanner.is_match(Token::LBrack) {
+            scanner.pop();
+            loop {
+                let pos = scanner.pos[scanner.cursor()];
+                let register = scanner.expect_ident()?.to_lowercase();
+                if !REGISTERS.contains(&register.as_str()) {
+                    return Err(format!("Error {:?}: {register} is not a register", pos));
+                }
+                if register == "zero" || register == "sp" {
+                    return Err(format!("Error {:?}: {register} cannot be saved around an asm block", pos));
+                }
+                saved.push(register);
+                if !scanner.is_match(Token::Comma) { break }
+                scanner.pop();
+            }
+            scanner.expect(&Token::RBrack)?;
+        }
+
+        for register in &saved {
+            scanner.emit(asm, format!("push {register}, sp"));
+        }
+        scanner.emit(asm, format!("{n}asm_{}_{}:", pos.0, pos.1, n = ".".repeat(scanner.nesting)));
+        scanner.nesting += 1;
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let mut code = String::new();
+            let mut rest = line;
+            while let Some(open) = rest.find('{') {
+                let close = rest[open..].find('}').ok_or(format!("Error {:?}: {{ without }} in asm block: {line}", pos))? + open;
+                code.push_str(&rest[..open]);
+                code.push_str(&asm_symbol(scanner, rest[open + 1..close].trim(), pos)?);
+                rest = &rest[close + 1..];
+            }
+            code.push_str(rest);
+            scanner.emit(asm, code);
+        }
+        scanner.nesting -= 1;
+        for register in saved.iter().rev() {
+            scanner.emit(asm, format!("pop {register}, sp"));
+        }
+        Ok(())
+    }
+
+    // What a name in an asm block stands for: the label of a variable or routine, or a constant
+    fn asm_symbol(scanner: &Scanner, name: &str, pos: (usize, usize)) -> Result<String, String> {
+        if name == "." {
+            return Ok(".".repeat(scanner.nesting));
+        }
+        if let Ok(id) = scanner.search(name.to_owned()) {
+            return Ok(match scanner.signature(&id) {
+                Some(_) => id.replace("global.", ""),
+                None => id
+            });
+        }
+        match scanner.search_const(name.to_owned()) {
+            Ok(id) if scanner.is_constant(&id) => Ok(id.replace("global.", "")),
+            _ => Err(format!("Error {:?}: {name} in asm block is not a variable, constant or routine", pos))
+        }
+    }
 
-        scanner.expect(&Token::Do)?;
-        statement(scanner, asm)?;
+    /* new = "new" "(" designator ")" ; dispose = "dispose" "(" designator ")" ;
+       new points the pointer to zeroed memory from the heap, dispose gives it
+       back and leaves the pointer nil */
+    fn memory_statement(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let new = scanner.pop() == Some(&Token::New);
+        scanner.expect(&Token::LParen)?;
+        let pos = scanner.pos[scanner.cursor()];
+        let id = scanner.expect_ident()?;
+        let id = scanner.search(id)?;
+        scanner.writable(&id)?;
+        let place = designator(scanner, asm, &id)?;
+        let target = match place.element {
+            Type::Pointer(i) if place.is_value() => scanner.target(i, pos)?,
+            _ => return Err(format!("Type error {:?}: {} is not a pointer", pos, place.name))
+        };
+        scanner.expect(&Token::RParen)?;
+
+        if new {
+            scanner.emit(asm, format!("li a0, {}", target.size()));
+        } else if place.memory {
+            scanner.emit(asm, format!("lw a0, 0({T})"));
+        } else {
+            scanner.emit(asm, format!("llw a0, {id}"));
+        }
+        scanner.emit(asm, "push ra, sp".to_string());
+        scanner.emit(asm, format!("jal ra, PL0_HEAP.{}", if new { "new" } else { "dispose" }));
+        scanner.emit(asm, "pop ra, sp".to_string());
 
-        scanner.nesting -= 1;
-        scanner.emit(asm, format!("j {n}while", n = ".".repeat(scanner.nesting)));
-        scanner.emit(asm, format!("{n}end_while:", n = ".".repeat(scanner.nesting)));
+        let value = if new { "a0" } else { "zero" };
+        if place.memory {
+            scanner.emit(asm, format!("sw {value}, 0({T})")); // the runtime leaves T alone
+        } else {
+            scanner.emit(asm, format!("ssw {value}, {id}, {T}"));
+        }
         Ok(())
     }
 
     fn exit_statement(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
         scanner.pop();
-        expression(scanner, asm)?;
+        integer_expression(scanner, asm)?;
         scanner.emit(asm, format!("mv a0, {A}"));
-        scanner.emit(asm, format!("j crt0.exit")); //TODO: add a better exit point
+        scanner.emit(asm, "j crt0.exit".to_string()); //TODO: add a better exit point
         Ok(())
     }
 
 
-    /*condition = "odd" expression |
-            expression ("="|"#"|"<"|"<="|">"|">=") expression ;*/
+    /*expression = "odd" simple | simple [ ("="|"#"|"<"|"<="|">"|">=") simple ] ;
+      Comparisons give a boolean, 0 or 1 in A, so they are expressions of their own */
 
     fn compare(scanner: &mut Scanner) -> Result<String, String> {
         let lhs = B; 
@@ -764,23 +2693,89 @@ mod parser {
         }
     }
 
+    fn is_comparison(scanner: &Scanner) -> bool {
+        matches!(scanner.peek(), Some(Token::Equals) | Some(Token::Hash) | Some(Token::Less) | Some(Token::LessEq) | Some(Token::Great) | Some(Token::GreatEq))
+    }
+
+    // The conditions of if, while, repeat and do
     fn condition(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        match expression(scanner, asm)? {
+            Type::Boolean => Ok(()),
+            t => Err(format!("Type error {:?}: a condition must be boolean, got {}; compare it, as in `x # 0`", pos, t.name()))
+        }
+    }
+
+    // An expression that must be a number: indices, bounds, output...
+    fn integer_expression(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let pos = scanner.pos[scanner.cursor()];
+        match expression(scanner, asm)? {
+            Type::Integer | Type::Char => Ok(()),
+            t => Err(format!("Type error {:?}: expected an integer, got {}", pos, t.name()))
+        }
+    }
+
+    /* An expression whose value is stored into a variable of the type target. Integers
+       only go into chars as constants from 0 to 255, others are converted with chr */
+    fn stored_expression(scanner: &mut Scanner, asm: &mut Vec<String>, target: Type) -> Result<Type, String> {
+        let pos = scanner.pos[scanner.cursor()];
+        let constant = constant_expression(scanner);
+        let t = expression(scanner, asm)?;
+        if target == Type::Char && t == Type::Integer {
+            match constant {
+                Some(c) if (0..=255).contains(&c) => (),
+                Some(c) => return Err(format!("Type error {:?}: {c} does not fit in a char", pos)),
+                None => return Err(format!("Type error {:?}: cannot store an integer into a char, convert it with chr", pos))
+            }
+        }
+        Ok(t)
+    }
+
+    // The value of the next expression when it is a constant on its own, as in c := 65
+    fn constant_expression(scanner: &Scanner) -> Option<i32> {
+        let value = constant_factor(scanner)?;
+        match scanner.tokens.get(scanner.cursor() + 1) {
+            Some(Token::Equals | Token::Hash | Token::Less | Token::LessEq | Token::Great | Token::GreatEq | Token::Plus
+                | Token::Minus | Token::Times | Token::Slash | Token::Mod | Token::And | Token::Or) => None,
+            _ => Some(value)
+        }
+    }
+
+    fn expression(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<Type, String> {
         if scanner.is_match(Token::Odd) {
+            let pos = scanner.pos[scanner.cursor()];
             scanner.pop();
-            expression(scanner, asm)?;
+            let t = simple_expression(scanner, asm)?;
+            if !matches!(t, Type::Integer | Type::Char) {
+                return Err(format!("Type error {:?}: 'odd' takes an integer, got {}", pos, t.name()));
+            }
             scanner.emit(asm, format!("andi {A}, {A}, 1"));
-        } else {
-            expression(scanner, asm)?;
-            scanner.emit(asm, format!("push {A}, sp"));
-            let comparison= compare(scanner)?;
-            expression(scanner, asm)?;
-            scanner.emit(asm, format!("pop {B}, sp"));
-            scanner.emit(asm, comparison);
+            return Ok(Type::Boolean);
         }
-        Ok(())
+
+        let lhs = simple_expression(scanner, asm)?;
+        if !is_comparison(scanner) {
+            return Ok(lhs);
+        }
+
+        let pos = scanner.pos[scanner.cursor()];
+        let ordering = !matches!(scanner.peek(), Some(Token::Equals) | Some(Token::Hash));
+        scanner.emit(asm, format!("push {A}, sp"));
+        let comparison = compare(scanner)?;
+        let rhs = simple_expression(scanner, asm)?;
+        scanner.emit(asm, format!("pop {B}, sp"));
+        let lhs = if lhs == Type::Nil { rhs } else { lhs };
+        if !scanner.compatible(lhs, rhs) {
+            return Err(format!("Type error {:?}: cannot compare {} with {}", pos, lhs.name(), rhs.name()));
+        }
+        if ordering && !matches!(lhs, Type::Integer | Type::Char) {
+            return Err(format!("Type error {:?}: {} can only be compared with = and #", pos, lhs.name()));
+        }
+        scanner.emit(asm, comparison);
+        Ok(Type::Boolean)
     }
 
-    /*expression = [ "+"|"-"] term { ("+"|"-") term};*/
+    /*simple = [ "+"|"-"|"not" ] term { ("+"|"-"|"or") term } ;*/
 
     fn add_sub_or(scanner: &mut Scanner) -> Result<String, String> {
         let pos = scanner.pos[scanner.cursor];
@@ -792,39 +2787,70 @@ mod parser {
         }
     }
 
-    fn expression(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+    /* Arithmetic takes integers, 'and', 'or' and 'not' are logical on booleans and bitwise on integers.
+       Chars are numbers here, and the result is an integer */
+    fn operand_types(pos: (usize, usize), op: &Token, lhs: Type, rhs: Type) -> Result<Type, String> {
+        let logical = matches!(op, Token::And | Token::Or | Token::Not);
+        let op = match op {
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Times => "*",
+            Token::Slash => "/",
+            Token::Mod => "'mod'",
+            Token::And => "'and'",
+            Token::Or => "'or'",
+            _ => "'not'"
+        };
+        match (lhs, rhs) {
+            (Type::Boolean, Type::Boolean) if logical => Ok(Type::Boolean),
+            (Type::Integer | Type::Char, Type::Integer | Type::Char) => Ok(Type::Integer),
+            _ if logical => Err(format!("Type error {:?}: {op} takes two integers or two booleans, got {} and {}", pos, lhs.name(), rhs.name())),
+            _ => Err(format!("Type error {:?}: {op} takes integers, got {} and {}", pos, lhs.name(), rhs.name()))
+        }
+    }
 
-        match scanner.peek() {
-            Some(&Token::Plus) => {
+    fn simple_expression(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<Type, String> {
+        let pos = scanner.pos[scanner.cursor()];
+        let mut t = match scanner.peek().cloned() {
+            Some(Token::Plus) => {
                 scanner.pop();
-                term(scanner, asm)?;
+                operand_types(pos, &Token::Plus, term(scanner, asm)?, Type::Integer)?
             }
-            Some(&Token::Minus) => {
+            Some(Token::Minus) => {
                 scanner.pop();
-                term(scanner, asm)?;
+                let t = operand_types(pos, &Token::Minus, term(scanner, asm)?, Type::Integer)?;
                 scanner.emit(asm, format!("not {A}, {A}")); // Maybe would be handy allow to add an immediate here
                 scanner.emit(asm, format!("addi {A}, {A}, 1"));
+                t
             } 
-            Some(&Token::Not) => {
+            Some(Token::Not) => {
                 scanner.pop();
-                term(scanner, asm)?;
-                scanner.emit(asm, format!("not {A}, {A}"));
+                let t = term(scanner, asm)?;
+                let t = operand_types(pos, &Token::Not, t, t)?;
+                match t {
+                    Type::Boolean => scanner.emit(asm, format!("xori {A}, {A}, 1")),
+                    _ => scanner.emit(asm, format!("not {A}, {A}"))
+                }
+                t
             }
             _ =>  term(scanner, asm)?
-        }
+        };
 
         while scanner.is_match(Token::Plus) || scanner.is_match(Token::Minus) ||  scanner.is_match(Token::Or) {
+            let pos = scanner.pos[scanner.cursor()];
+            let op = scanner.peek().cloned().unwrap();
             scanner.emit(asm, format!("push {A}, sp"));
             let expression = add_sub_or(scanner)?;
-            term(scanner, asm)?;
+            let rhs = term(scanner, asm)?;
             scanner.emit(asm, format!("pop {B}, sp"));
             scanner.emit(asm, expression);
+            t = operand_types(pos, &op, t, rhs)?;
         }
 
-        Ok(())
+        Ok(t)
     }
 
-    /*term = factor {("*"|"/") factor};*/
+    /*term = factor {("*"|"/"|"mod"|"and") factor};*/
 
     fn mul_div_mod_and(scanner: &mut Scanner) -> Result<String, String> {
         let pos = scanner.pos[scanner.cursor];
@@ -838,55 +2864,180 @@ mod parser {
         }
     }
     
-    fn term(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
-        factor(scanner, asm)?;
-        while scanner.is_match(Token::Times) || scanner.is_match(Token::Slash) {
+    fn term(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<Type, String> {
+        let mut t = factor(scanner, asm)?;
+        while scanner.is_match(Token::Times) || scanner.is_match(Token::Slash) || scanner.is_match(Token::Mod) || scanner.is_match(Token::And) {
+            let pos = scanner.pos[scanner.cursor()];
+            let op = scanner.peek().cloned().unwrap();
             scanner.emit(asm, format!("push {A}, sp"));
             let term = mul_div_mod_and(scanner)?;
-            factor(scanner, asm)?;
+            let division = matches!(op, Token::Slash | Token::Mod);
+            if division && constant_factor(scanner) == Some(0) {
+                scanner.warn(scanner.pos[scanner.cursor()], "division by zero, the divisor is the constant 0");
+            }
+            let rhs = factor(scanner, asm)?;
             scanner.emit(asm, format!("pop {B}, sp"));
+            if division && scanner.options.div_check {
+                div_check(scanner, asm, pos);
+            }
             scanner.emit(asm, term);
+            t = operand_types(pos, &op, t, rhs)?;
+        }
+        Ok(t)
+    }
+
+    // The value of the next factor when it is a constant on its own, as in x / 0 or x mod SIZE
+    fn constant_factor(scanner: &Scanner) -> Option<i32> {
+        if matches!(scanner.tokens.get(scanner.cursor() + 1), Some(Token::LParen | Token::LBrack | Token::Point | Token::Caret)) {
+            return None;
+        }
+        match scanner.peek()? {
+            Token::Number{val} => Some(*val),
+            Token::Ident{val} if scanner.search(val.clone()).is_err() => {
+                scanner.search_const(val.clone()).ok().and_then(|id| scanner.constant_value(&id))
+            }
+            _ => None
+        }
+    }
+
+    // Traps when the divisor in A is 0. The runtime gets in a0 a descriptor of the
+    // division: the source line followed by the name of the procedure
+    fn div_check(scanner: &Scanner, asm: &mut Vec<String>, pos: (usize, usize)) {
+        let site = scanner.label("division", pos);
+        let name = scanner.scope_name.replace("global.", "");
+        scanner.emit(asm, format!("#[pragma(string_litteral)]{site}: #d32 {line}\n#d \"{name}\\0\"\n#align 32", line = pos.0));
+        scanner.emit(asm, format!("la a0, {site}"));
+        scanner.emit(asm, format!("beq {A}, zero, PL0_TRAP.division"));
+    }
+
+    /* len = "len" "(" ident [ "," number ] ")" ;
+       the length of an array, or of one of its dimensions. `len` is not reserved */
+    fn len(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        scanner.expect(&Token::LParen)?;
+        let id = scanner.expect_ident()?;
+        let id = scanner.search(id)?;
+        let array = scanner.array(&id)?;
+        let dim = if scanner.is_match(Token::Comma) {
+            scanner.pop();
+            let pos = scanner.pos[scanner.cursor()];
+            let dim = constant_val(scanner)?;
+            if dim < 0 || dim as usize >= array.dims.len() {
+                return Err(format!("Error {:?}: {id} has {} dimensions, there is no dimension {dim}", pos, array.dims.len()));
+            }
+            dim as usize
+        } else {
+            0
+        };
+        scanner.expect(&Token::RParen)?;
+
+        if array.open {
+            scanner.emit(asm, format!("llw {A}, {}", array.len_label(dim)));
+        } else {
+            scanner.emit(asm, format!("li {A}, {}", array.dims[dim])); // sizes of declared arrays are known while compiling
         }
         Ok(())
     }
 
-    /*factor = ident | number | "(" expression ")";*/
+    /* chr = "chr" "(" expression ")" ;
+       the char of the low byte of an integer. `chr` is not reserved */
+    fn chr(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        scanner.expect(&Token::LParen)?;
+        integer_expression(scanner, asm)?;
+        scanner.expect(&Token::RParen)?;
+        scanner.emit(asm, format!("andi {A}, {A}, 0xff"));
+        Ok(())
+    }
+
+    /* peek = ( "peekb" | "peekw" ) "(" expression ")" ;
+       the byte or the word at an address of the devices. Neither is reserved */
+    fn peek(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+        let load = if scanner.expect_ident()?.to_lowercase() == "peekb" { "lbud" } else { "lwd" };
+        scanner.expect(&Token::LParen)?;
+        device_address(scanner, asm)?;
+        scanner.expect(&Token::RParen)?;
+        scanner.emit(asm, format!("{load} {A}, 0({A})"));
+        Ok(())
+    }
+
+    /*factor = ident [ index ] | function | number | "true" | "false" | "(" expression ")";
+      one character strings and chr are chars, the other numbers are integers */
 
-    fn factor(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<(), String> {
+    fn factor(scanner: &mut Scanner, asm: &mut Vec<String>) -> Result<Type, String> {
         match scanner.peek() {
+            Some(Token::Ident{val}) if val.to_lowercase() == "len" && scanner.tokens.get(scanner.cursor() + 1) == Some(&Token::LParen)
+                && scanner.search(val.clone()).is_err() => {
+                scanner.pop();
+                len(scanner, asm)?;
+                Ok(Type::Integer)
+            },
+            Some(Token::Ident{val}) if val.to_lowercase() == "chr" && scanner.tokens.get(scanner.cursor() + 1) == Some(&Token::LParen)
+                && scanner.search(val.clone()).is_err() => {
+                scanner.pop();
+                chr(scanner, asm)?;
+                Ok(Type::Char)
+            },
+            Some(Token::Ident{val}) if ["peekb", "peekw"].contains(&val.to_lowercase().as_str())
+                && scanner.tokens.get(scanner.cursor() + 1) == Some(&Token::LParen) && scanner.search(val.clone()).is_err() => {
+                peek(scanner, asm)?;
+                Ok(Type::Integer)
+            },
             Some(Token::Ident{..}) => {
-                let id = scanner.expect_ident()?;
-                match scanner.search(id.clone()) {
+                let id = scanner.expect_name()?;
+                let t = match scanner.search(id.clone()) {
+                    Ok(id) if scanner.signature(&id).is_some() => {
+                        let sig = scanner.signature(&id).unwrap().clone();
+                        if sig.result.is_none() {
+                            return Err(format!("Type error {:?}: {} is a procedure and has no value, use 'call'", scanner.pos[scanner.cursor() - 1], id.replace("global.", "")));
+                        }
+                        call_routine(scanner, asm, &sig)?;
+                        scanner.type_of(&id)
+                    }
                     Ok(id) => {
-                        if scanner.is_match(Token::LBrack) {
-                            scanner.is_array(id.clone())?;
-                            scanner.pop();
-                            expression(scanner, asm)?;
-                            scanner.expect(&Token::RBrack)?;
-                            scanner.emit(asm, format!("muli {A}, {A}, 4"));
-                            scanner.emit(asm, format!("la {T}, {id}"));
-                            scanner.emit(asm, format!("add {T}, {A}, {T}"));
-                            scanner.emit(asm, format!("lw {A}, 0({T})"));
+                        if scanner.is_match(Token::LParen) {
+                            return Err(format!("Type error {:?}: {} is a variable, only functions can be called", scanner.pos[scanner.cursor() - 1], id.replace("global.", "")));
                         }
-                        else { scanner.emit(asm, format!("llw {A}, {id}")) }
+                        let pos = scanner.pos[scanner.cursor() - 1];
+                        let place = designator(scanner, asm, &id)?;
+                        if !place.is_value() {
+                            let what = if place.array.is_some() { "an array" } else { "a record" };
+                            return Err(format!("Type error {:?}: {} is {what}, it has no value as a whole", pos, place.name));
+                        }
+                        if place.memory {
+                            scanner.emit(asm, format!("{load} {A}, 0({T})", load = place.element.load()));
+                        } else {
+                            scanner.emit(asm, format!("llw {A}, {id}"));
                         }
+                        place.element
+                    }
                     Err(_) => {
                         let id = scanner.search_const(id)?.replace("global.", "");
-                        scanner.emit(asm, format!("li {A}, {id}"))
+                        scanner.emit(asm, format!("li {A}, {id}"));
+                        Type::Integer
                     }
-                }
-                Ok(())
+                };
+                Ok(t)
             },
-            Some(Token::Number{..}) => {
-                let num = scanner.expect_num()?;
+            Some(Token::Number{..}) | Some(Token::Str(..)) => {
+                let t = if matches!(scanner.peek(), Some(Token::Str(..))) { Type::Char } else { Type::Integer };
+                let num = constant_val(scanner)?;
                 scanner.emit(asm, format!("li {A}, {num}"));
-                Ok(())
+                Ok(t)
+            },
+            Some(Token::Nil) => {
+                scanner.pop();
+                scanner.emit(asm, format!("mv {A}, zero"));
+                Ok(Type::Nil)
+            },
+            Some(Token::True) | Some(Token::False) => {
+                let val = scanner.pop() == Some(&Token::True);
+                scanner.emit(asm, format!("li {A}, {}", val as i32));
+                Ok(Type::Boolean)
             },
             Some(Token::LParen) => {
                 scanner.pop();
-                expression(scanner, asm)?;
+                let t = expression(scanner, asm)?;
                 scanner.expect(&Token::RParen)?;
-                Ok(())
+                Ok(t)
             },
             _ => Err(format!("Error {:?}: Expected Factor", scanner.pos[scanner.cursor()]))
         }
@@ -895,17 +3046,306 @@ mod parser {
 
 }
 
+mod runtime {
+
+    // The runtime is kept in std/ and built into the compiler, so programs assemble on their own
+    const MASTER: &str = include_str!("../std/master.asm");
+    const SYS: &str = include_str!("../std/sys.asm");
+    const CRT0: &str = include_str!("../std/crt0.asm");
+
+    /* A routine of crt0.asm, or a part of one made of parts (PL0_TRAP.nil, crt0.puts), with the
+       comments before it. Constants outside of any routine have no name and are always kept */
+    struct Piece {
+        name: Option<String>,
+        lines: Vec<&'static str>
+    }
+
+    fn pieces() -> Vec<Piece> {
+        let mut pieces: Vec<Piece> = vec![];
+        let mut routine = String::new();
+        let mut split = false;
+        let mut comments = vec![];
+        for line in CRT0.lines() {
+            if line.starts_with("#include") { // the runtime is copied whole instead
+                continue;
+            }
+            let label = line.trim_end().strip_suffix(':');
+            if let Some(name) = label.filter(|name| !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')) {
+                routine = name.to_owned();
+                split = false;
+                comments.push(line);
+                pieces.push(Piece { name: Some(routine.clone()), lines: std::mem::take(&mut comments) });
+            } else if line.starts_with(';') {
+                comments.push(line);
+            } else if !line.is_empty() && !line.starts_with(char::is_whitespace) {
+                comments.push(line);
+                match pieces.last_mut() {
+                    Some(Piece { name: None, lines }) => lines.append(&mut comments),
+                    _ => pieces.push(Piece { name: None, lines: std::mem::take(&mut comments) })
+                }
+            } else if let Some(part) = line.strip_prefix("    .").and_then(|l| l.split_once(':')).map(|(part, _)| part)
+                .filter(|_| !routine.is_empty()) {
+                // A routine is made of parts when its code, if any, cannot run into the first of them
+                let last = pieces.last().unwrap();
+                split |= last.name.as_deref() == Some(routine.as_str()) && last.lines.iter()
+                    .map(|l| l.split(';').next().unwrap().trim())
+                    .rfind(|l| !l.is_empty() && l.strip_suffix(':') != Some(routine.as_str()))
+                    .is_none_or(|l| l.starts_with("j ") || l.starts_with("jalr zero,") || l == "sysret");
+                if split {
+                    pieces.push(Piece { name: Some(format!("{routine}.{part}")), lines: vec![line] });
+                } else {
+                    pieces.last_mut().unwrap().lines.push(line);
+                }
+            } else {
+                pieces.last_mut().unwrap().lines.push(line);
+            }
+        }
+        pieces
+    }
+
+    // The pieces named by a line: crt0.puts, PL0_TRAP.nil, or .free inside PL0_HEAP
+    fn references(pieces: &[Piece], line: &str, routine: &str) -> Vec<usize> {
+        let code = line.split([';', '"']).next().unwrap();
+        let find = |name: &str| pieces.iter().position(|p| p.name.as_deref() == Some(name));
+        code.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .filter(|word| !word.is_empty() && !word.starts_with(".."))
+            .filter_map(|word| match word.strip_prefix('.') {
+                Some(_) => find(&format!("{routine}{word}")),
+                None => find(word).or_else(|| find(word.split('.').next().unwrap()))
+            })
+            .collect()
+    }
+
+    /* The assembly of the runtime, with the routines the program uses and those they use,
+       and apart the data of the runtime they use (PL0_DATA), for the data section */
+    pub fn runtime(text: &[String]) -> (Vec<String>, Vec<String>) {
+        let pieces = pieces();
+        let mut used = vec![false; pieces.len()];
+        let mut pending: Vec<usize> = pieces.iter().position(|p| p.name.as_deref() == Some("crt0")).into_iter().collect();
+        pending.extend(text.iter().flat_map(|line| references(&pieces, line, "")));
+        while let Some(i) = pending.pop() {
+            if used[i] {
+                continue;
+            }
+            used[i] = true;
+            let name = pieces[i].name.clone().unwrap();
+            let routine = name.split('.').next().unwrap();
+            pending.extend(pieces[i].lines.iter().flat_map(|line| references(&pieces, line, routine)));
+            if routine != name { // the parts of a routine need its label
+                pending.extend(references(&pieces, routine, ""));
+            }
+        }
+
+        let mut asm: Vec<String> = MASTER.lines().chain(SYS.lines()).map(str::to_owned).collect();
+        let mut data = vec![];
+        for (piece, used) in pieces.iter().zip(used) {
+            let lines = piece.lines.iter().map(|line| line.to_string());
+            if piece.name.as_deref().is_some_and(|name| name.split('.').next() == Some("PL0_DATA")) {
+                if used {
+                    data.extend(lines);
+                }
+            } else if used || piece.name.is_none() {
+                asm.extend(lines);
+            }
+        }
+        (asm, data)
+    }
+}
+
+mod layout {
+    use std::path::Path;
+    use super::lexer::number;
+
+    // Where a program goes in the memory of the Taleä System, set from the command line or a file
+    #[derive(Debug, Clone)]
+    pub struct Layout {
+        pub code: u32, // origin of crt0 and the program
+        pub data: Option<u32>, // right after the code when not given
+        pub stack: u32,
+        pub heap: u32, // only reserved when new is used
+        pub memory: u32
+    }
+
+    impl Default for Layout {
+        fn default() -> Self {
+            Self { code: 0, data: None, stack: 1024, heap: 4096, memory: 0x100_0000 }
+        }
+    }
+
+    impl Layout {
+        pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+            let value = match number(&value.trim().to_lowercase()) {
+                Some(Ok(n)) => n as u32,
+                Some(Err(err)) => return Err(format!("Layout error: {err}")),
+                None => return Err(format!("Layout error: {key} takes a number, got '{value}'"))
+            };
+            match key {
+                "code" => self.code = value,
+                "data" => self.data = Some(value),
+                "stack" => self.stack = value,
+                "heap" => self.heap = value,
+                "memory" => self.memory = value,
+                _ => return Err(format!("Layout error: unknown setting {key}, expected code, data, stack, heap or memory"))
+            }
+            Ok(())
+        }
+
+        /* A layout file holds a setting per line, `key = value`, where the values are
+           written like the numbers of PL/0. Comments start with ';', as in sys.asm */
+        pub fn read(&mut self, file: &Path) -> Result<(), String> {
+            let text = std::fs::read_to_string(file).map_err(|err| format!("Layout error: cannot read {}: {err}", file.display()))?;
+            for (lineno, line) in text.lines().enumerate() {
+                let line = line.split(';').next().unwrap().trim();
+                if line.is_empty() {
+                    continue;
+                }
+                match line.split_once('=') {
+                    Some((key, value)) => self.set(key.trim(), value),
+                    None => Err(format!("Layout error: expected 'key = value', got '{line}'"))
+                }.map_err(|err| format!("{}:{}: {err}", file.display(), lineno + 1))?;
+            }
+            Ok(())
+        }
+
+        // Fails when the program, its data, the stack or the heap do not fit where they go
+        pub fn check(&self, code: &[String], data: &[String]) -> Result<(), String> {
+            for (name, value) in [("code", self.code), ("data", self.data.unwrap_or(0)), ("stack", self.stack), ("heap", self.heap)] {
+                if value % 4 != 0 {
+                    return Err(format!("Layout error: {name} must be a multiple of 4, got {value}"));
+                }
+            }
+            if self.stack == 0 {
+                return Err("Layout error: the stack cannot be empty".to_string());
+            }
+
+            let code_end = self.code as u64 + size(code)?;
+            let data_start = self.data.map_or(code_end, |data| data as u64);
+            let data_end = data_start + size(data)?;
+            if self.data.is_some() && data_start < code_end && (self.code as u64) < data_end {
+                return Err(format!("Layout error: the data, from {data_start:#x} to {data_end:#x}, overlap the code, from {:#x} to {code_end:#x}", self.code));
+            }
+            let end = code_end.max(data_end);
+            if end > self.memory as u64 {
+                return Err(format!("Layout error: the program takes memory up to {end:#x}, but there are only {:#x} bytes", self.memory));
+            }
+            Ok(())
+        }
+    }
+
+    // Pseudo instructions of master.asm taking two words
+    const LONG: [&str; 10] = ["li", "la", "llb", "llh", "llw", "ssb", "ssh", "ssw", "call", "tail"];
+
+    // Bytes kept above the stack, which starts below .stack and grows down
+    pub const STACK_GUARD: u32 = 12;
+
+    /* The bytes some assembly takes at most, counting every pseudo instruction as two
+       words (li takes one when the value is small). Rule definitions take nothing, and
+       reservations must be numbers, as the compiler writes them */
+    fn size(asm: &[String]) -> Result<u64, String> {
+        let mut size = 0;
+        let mut rules = 0;
+        for line in asm.iter().flat_map(|entry| entry.lines()) { // string literals span lines
+            let line = line.trim();
+            if line.starts_with("#ruledef") || line.starts_with("#subruledef") || rules > 0 {
+                rules += line.matches('{').count() as i64 - line.matches('}').count() as i64;
+                continue;
+            }
+            let line = match line.split_once(':') {
+                Some((label, rest)) if !label.contains(char::is_whitespace) && !label.starts_with('#') => rest.trim(),
+                _ => line
+            };
+            if let Some(text) = line.strip_prefix("#d").map(str::trim).filter(|text| text.starts_with('"')) {
+                size += string_size(text);
+                continue;
+            }
+            let code = line.split(';').next().unwrap().trim();
+            if code.is_empty() || code.contains('=') {
+                continue;
+            }
+            let (word, operands) = code.split_once(char::is_whitespace).unwrap_or((code, ""));
+            size += match word {
+                "#res" => parse(operands).ok_or(format!("Layout error: cannot size '{code}', #res takes a number of bytes"))?,
+                "#align" => (4 - size % 4) % 4,
+                "#d8" | "#d16" | "#d32" | "#d64" => word[2..].parse::<u64>().unwrap() / 8 * operands.split(',').count() as u64,
+                "#addr" | "#once" => 0,
+                _ if word.starts_with('#') => return Err(format!("Layout error: cannot size '{code}'")),
+                _ if LONG.contains(&word) => 8,
+                _ => 4
+            };
+        }
+        Ok(size)
+    }
+
+    // The bytes of a string of customasm, "with \n escapes\0"
+    fn string_size(text: &str) -> u64 {
+        let text = text.trim_matches('"');
+        let mut size = 0;
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c == '\\' && chars.next() == Some('x') {
+                chars.nth(1);
+            }
+            size += c.len_utf8() as u64;
+        }
+        size
+    }
+
+    fn parse(n: &str) -> Option<u64> {
+        number(&n.trim().to_lowercase()).and_then(Result::ok).map(|n| n as u32 as u64)
+    }
+}
+
 fn main() -> io::Result<()> {
     use crate::parser::*;
     use crate::lexer::tokenize;
 
+    let usage = "Usage: pl0 [--bounds-check] [--stack-check] [--div-check] [-I directory]... [--layout file] [--code|--data|--stack|--heap|--memory number]... < program.pl0";
+    let mut options = Options::default();
+    let mut layout = Layout::default(); // later settings override earlier ones
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bounds-check" => options.bounds_check = true,
+            "--stack-check" => options.stack_check = true,
+            "--div-check" => options.div_check = true,
+            "-I" => match args.next() {
+                Some(dir) => options.path.push(dir.into()),
+                None => abort(&format!("-I takes the directory to search units in\n{usage}"))
+            },
+            "--layout" => match args.next() {
+                Some(file) => if let Err(err) = layout.read(std::path::Path::new(&file)) {
+                    abort(&err)
+                },
+                None => abort(&format!("--layout takes the file of the memory layout\n{usage}"))
+            },
+            "--code" | "--data" | "--stack" | "--heap" | "--memory" => match args.next() {
+                Some(value) => if let Err(err) = layout.set(&arg[2..], &value) {
+                    abort(&format!("{err}\n{usage}"))
+                },
+                None => abort(&format!("{arg} takes a number\n{usage}"))
+            },
+            _ => abort(&format!("Unknown option {arg}\n{usage}"))
+        }
+    }
+
     let mut source = String::new();
     io::stdin().read_to_string(&mut source)?;
 
-    let source = tokenize(source);
+    let source = match tokenize(source) {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            abort(&err);
+            return Ok(())
+        }
+    };
     let mut compiled = vec![];
+    let mut warnings = vec![];
 
-    match compile("\t", source, &mut compiled) {
+    let code = compile("\t", source, &mut compiled, &mut warnings, options);
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+    match code {
         Ok(code) => compiled = code,
         Err(err) => {
             abort(&err);
@@ -913,13 +3353,32 @@ fn main() -> io::Result<()> {
         }
     }
 
+    match link(compiled, &layout) {
+        Ok(program) => for line in program {
+            println!("{}", line);
+        },
+        Err(err) => abort(&err)
+    }
+
+    Ok(())
+}
 
+/* Lays out the compiled program: the runtime and the code, then the string literals and
+   the variables, gathered from their pragmas, the stack and the heap */
+fn link(compiled: Vec<String>, layout: &Layout) -> Result<Vec<String>, String> {
+    use crate::parser::{A, B, T};
+    use crate::runtime::runtime;
+    use crate::layout::STACK_GUARD;
 
     let mut text = vec![];
     let mut data = vec![];
     let mut string_litterals = vec![];
+    let mut handlers = vec![];
     for line in compiled {
-        if line.contains("#[pragma(var)]") {
+        if line.contains("#[pragma(interrupt)]") {
+            let (source, handler) = line.replace("#[pragma(interrupt)]", "").trim().split_once(' ').map(|(s, h)| (s.to_owned(), h.to_owned())).unwrap();
+            handlers.push((source, handler));
+        } else if line.contains("#[pragma(var)]") {
             data.push(line.replace("#[pragma(var)]", ""));
         } else if line.contains("#[pragma(string_litteral)]") {
             string_litterals.push(line.replace("#[pragma(string_litteral)]", ""));
@@ -930,17 +3389,15 @@ fn main() -> io::Result<()> {
 
     let mut variables = vec![];
     for line in data {
-        let (scope, name) = line.split_once(";").clone().unwrap();
+        let (scope, name) = line.split_once(";").unwrap();
         variables.push((scope.trim().to_owned(), name.trim().to_owned()));
     }
 
-    variables.sort_by_key(|decl| {
-        let scope = decl.0.clone();
-        let scope: Vec<&str> = scope.split(".").collect();
-        scope.len()
-    });
+    // Group every scope right after its parent, so the local labels nest like the procedures
+    variables.sort_by(|a, b| a.0.split(".").cmp(b.0.split(".")));
 
     let mut var_table = vec!["global:".to_owned()];
+    let mut labelled = vec!["global".to_owned()];
     let mut current_scope = String::new();
     let mut nesting = 0;
     for (scope, name) in variables {
@@ -948,8 +3405,18 @@ fn main() -> io::Result<()> {
             current_scope = scope.clone();
             let s: Vec<&str> = scope.split(".").collect();
             nesting = s.len() - 1;
-            let s = format!("{t}{n}{s}:", t="\t".repeat(nesting), n=".".repeat(nesting), s=s.last().unwrap());
-            var_table.push(s);
+            for depth in 1..s.len() { // parents without variables of their own still need a label
+                let parent = s[..=depth].join(".");
+                if !labelled.contains(&parent) {
+                    var_table.push(format!("{t}{n}{s}:", t="\t".repeat(depth), n=".".repeat(depth), s=s[depth]));
+                    labelled.push(parent);
+                }
+            }
+        }
+
+        if name.starts_with('#') { // directives continuing the previous variable
+            var_table.push(format!("\t\t{t}{name}", t="\t".repeat(nesting)));
+            continue;
         }
 
         if scope == "global" {
@@ -957,53 +3424,356 @@ fn main() -> io::Result<()> {
             continue;
         }
 
-        var_table.push(format!("\t{t}{n}{name}",  t="\t".repeat(nesting), n=".".repeat(nesting)))
+        var_table.push(format!("\t{t}.{name}",  t="\t".repeat(nesting))) // the name is dotted to the depth of its scope less one
     }
     
-    println!("#include \"std/crt0.asm\"");
-    println!("; section TEXT --------");
-
-    for line in text {
-        println!("{}", line);
-    }
-    println!("");
-    println!("; ENTRY POINT -------");
-    println!("Start: ");
-    println!("\tmv {A}, zero");
-    println!("\tmv {B}, zero");
-    println!("\tmv {T}, zero");
-
-    println!("\tjal ra, main");//Main exits with 0 implicitly
-    println!("\tmv a0, zero"); //TODO: maybe move exit code to a system vvariable
-    println!("\tj crt0.exit");
-    println!("");
-    println!("; section DATA --------");
-    println!("; String Litterals-----");
+    // The layout is checked once the size of the program is known
+    let heap = text.iter().any(|line| line.contains("PL0_HEAP.new"));
+    let (runtime, runtime_data) = runtime(&text);
+    let mut code = vec![format!("#addr {:#x}", layout.code)];
+    code.extend(runtime);
+    code.push("; section TEXT --------".to_string());
+    code.extend(text);
+    code.push(String::new());
+    code.push("; ENTRY POINT -------".to_string());
+    code.push("Start: ".to_string());
+    code.push(format!("\tmv {A}, zero"));
+    code.push(format!("\tmv {B}, zero"));
+    code.push(format!("\tmv {T}, zero"));
+    if !handlers.is_empty() { // replacing the default handlers of crt0
+        code.push("\tli t1, _IVT".to_string());
+    }
+    for (source, handler) in handlers {
+        code.push(format!("\tla a0, {handler}"));
+        code.push(format!("\tswd a0, {source}(t1)"));
+    }
+
+    code.push("\tjal ra, main".to_string());//Main exits with 0 implicitly
+    code.push("\tmv a0, zero".to_string()); //TODO: maybe move exit code to a system vvariable
+    code.push("\tj crt0.exit".to_string());
+    code.push(String::new());
+
+    let mut data = vec!["; section DATA --------".to_string()];
+    if let Some(origin) = layout.data {
+        data.push(format!("#addr {origin:#x}"));
+    }
+    data.extend(runtime_data);
+    data.push("; String Litterals-----".to_string());
     for line in string_litterals {
-        println!("{}", line.trim());
+        data.push(line.trim().to_owned());
     }
-    println!("; Variables -----------");
-    println!("global:");
+    data.push("; Variables -----------".to_string());
+    data.push("global:".to_string());
     for line in var_table {
-        if line == "global:".to_owned() { continue }
-        println!("{}", line);
+        if line == "global:" { continue }
+        data.push(line);
     }
-    println!("; ---------------------");
-    println!("\t\t#align 32");
-    println!("\t\t#res 1024");
-    println!("\t\t#align 32");
-    println!("\t.stack:");
-    println!("\t\t#res 10");
-    println!("\t\t#align 32");
-    println!("\t.out_buff:");
-    println!("\t\t#res 10");
-    println!("\t\t#align 32");
-    println!("\t.in_buff:");
-    println!("\t\t#res 10");
-    println!("\t\t#align 32");
+    data.push("; ---------------------".to_string());
+    data.push("\t\t#align 32".to_string());
+    data.push("\t.stack_limit:".to_string()); // the stack grows down to here
+    data.push(format!("\t\t#res {}", layout.stack));
+    data.push("\t\t#align 32".to_string());
+    data.push("\t.stack:".to_string());
+    data.push(format!("\t\t#res {STACK_GUARD}"));
+    data.push("\t\t#align 32".to_string());
+    data.push("\t.heap:".to_string()); // only reserved when new is used
+    if heap {
+        data.push(format!("\t\t#res {}", layout.heap));
+    }
+    data.push("\t.heap_end:".to_string());
+
+    layout.check(&code, &data)?;
+    code.extend(data);
+    Ok(code)
+}
 
+#[cfg(test)]
+mod tests {
+    use crate::lexer::{tokenize, Token};
+    use crate::parser::{compile, Options};
+    use crate::layout::Layout;
 
+    fn tokens(source: &str) -> Vec<Token> {
+        tokenize(source.to_string()).unwrap().into_iter().map(|(tok, _, _)| tok).collect()
+    }
 
+    fn compiled(source: &str, options: Options) -> Result<Vec<String>, String> {
+        compile("\t", tokenize(source.to_string())?, &mut vec![], &mut vec![], options)
+    }
 
-    Ok(())
-}
\ No newline at end of file
+    fn emits(asm: &[String], text: &str) -> bool {
+        asm.iter().any(|line| line.contains(text))
+    }
+
+    #[test]
+    fn sibling_ifs_have_their_own_labels() {
+        let source = "var x; procedure main; begin if x = 1 then x := 2; if x = 2 then x := 3 else x := 1 end; .";
+        let asm = compiled(source, Options::default()).unwrap();
+        let labels: Vec<&str> = asm.iter().map(|line| line.trim()).filter(|line| line.ends_with(':')).collect();
+        assert_eq!(labels.iter().filter(|label| label.starts_with("..if_")).count(), 2);
+        assert!(labels.iter().all(|label| labels.iter().filter(|other| other == &label).count() == 1));
+    }
+
+    #[test]
+    fn numeric_literals() {
+        let expected: Vec<Token> = [255, 0xf800, 10, 15, 1_000_000, -1].iter().map(|&val| Token::Number{val}).collect();
+        assert_eq!(tokens("0xff $F800 0b1010 0o17 1_000_000 0xffffffff"), expected);
+    }
+
+    #[test]
+    fn character_literals_are_strings_of_one_byte() {
+        assert_eq!(tokens("'A'"), vec![Token::Str(vec![b'A'])]);
+    }
+
+    #[test]
+    fn literals_out_of_range() {
+        assert!(tokenize("4294967296".to_string()).unwrap_err().contains("does not fit in 32 bits"));
+        assert!(tokenize("0x".to_string()).unwrap_err().contains("invalid numeric literal"));
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(tokens(r"'a\n\t\\\'\0\x41'"), vec![Token::Str(b"a\n\t\\'\0A".to_vec())]);
+        assert_eq!(tokens("'It''s'"), vec![Token::Str(b"It's".to_vec())]);
+    }
+
+    #[test]
+    fn bad_escapes() {
+        assert!(tokenize(r"'\q'".to_string()).unwrap_err().contains("unknown escape sequence"));
+        assert!(tokenize(r"'\x4'".to_string()).unwrap_err().contains("two hexadecimal digits"));
+    }
+
+    fn case_of(arms: &str) -> Result<Vec<String>, String> {
+        compiled(&format!("procedure main; var x; begin case x of {arms} end end; ."), Options::default())
+    }
+
+    #[test]
+    fn case_labels_may_not_overlap() {
+        assert!(case_of("1..5: x := 1; 3: x := 2").unwrap_err().contains("duplicate case label"));
+        assert!(case_of("7: x := 1; 'a', 7: x := 2").unwrap_err().contains("duplicate case label"));
+        assert!(case_of("5..1: x := 1").unwrap_err().contains("empty case range"));
+    }
+
+    #[test]
+    fn dense_cases_jump_through_a_table() {
+        let asm = case_of("1: x := 1; 2: x := 2; 3..5: x := 3; 7: x := 4").unwrap();
+        assert!(emits(&asm, "case_table"));
+    }
+
+    #[test]
+    fn sparse_cases_compare_each_label() {
+        let asm = case_of("1: x := 1; 100: x := 2; 1000..2000: x := 3").unwrap();
+        assert!(!emits(&asm, "case_table"));
+        assert!(emits(&asm, "beq a2, a3"));
+        assert!(emits(&asm, "bgeu a3, a4"));
+    }
+
+    #[test]
+    fn recursive_calls_keep_their_own_frame() {
+        let asm = compiled(include_str!("../examples/recursion.pl0"), Options::default()).unwrap();
+        let call = asm.iter().position(|line| line.trim() == "jal ra, factorial").unwrap();
+        assert_eq!(asm[call - 9].trim(), "llw t1, global.factorial.n");
+        assert_eq!(asm[call + 2].trim(), "pop t1, sp");
+        assert_eq!(asm[call + 3].trim(), "ssw t1, global.factorial.n, t2");
+        assert!(!emits(&asm, "pragma(save)"));
+    }
+
+    #[test]
+    fn other_calls_save_nothing() {
+        let asm = compiled("function twice(n: integer): integer; begin return 2 * n end; procedure main; begin write twice(2) end; .", Options::default()).unwrap();
+        assert!(!emits(&asm, "llw t1, global.twice.n"));
+        assert!(!emits(&asm, "pragma(restore)"));
+    }
+
+    #[test]
+    fn recursive_routines_cannot_have_arrays() {
+        let error = compiled("procedure walk; var seen size 8; begin call walk end; procedure main; begin call walk end; .", Options::default()).unwrap_err();
+        assert!(error.contains("walk calls itself, but the array walk.seen"));
+    }
+
+    #[test]
+    fn pointers_may_name_types_declared_later_in_their_section() {
+        let list = "type L = ^Node; Node = record next: L; n: integer end; var p: L;";
+        assert!(compiled(&format!("{list} procedure main; begin new(p); p^.n := 1; p := p^.next end; ."), Options::default()).is_ok());
+        let error = compiled("type L = ^Node; var p: L; procedure main; begin end; .", Options::default()).unwrap_err();
+        assert!(error.contains("unknown type Node"));
+    }
+
+    #[test]
+    fn undeclared_pointer_targets_are_unknown_types() {
+        for program in [
+            "var p: ^Foo; procedure main; begin new(p) end; .",
+            "var p: ^Foo; procedure main; var x; begin x := p^ end; .",
+            "procedure f(p: ^Foo); begin end; procedure main; begin end; .",
+            "function f: ^Foo; begin end; procedure main; begin end; .",
+        ] {
+            assert!(compiled(program, Options::default()).unwrap_err().contains("unknown type Foo"), "{program}");
+        }
+    }
+
+    #[test]
+    fn unit_file_names_go_in_single_quotes() {
+        let error = compiled("import \"lib/strings.pl0\"; procedure main; begin end; .", Options::default()).unwrap_err();
+        assert!(error.contains("the file name of a unit goes in single quotes"));
+    }
+
+    #[test]
+    fn routines_are_not_assigned() {
+        let error = compiled("procedure p; begin end; procedure main; begin p := 1 end; .", Options::default()).unwrap_err();
+        assert!(error.ends_with(": p is a procedure, not a variable"), "{error}");
+        let error = compiled("function f; begin f := 1 end; procedure main; begin end; .", Options::default()).unwrap_err();
+        assert!(error.ends_with(": f is a function, its result is given with 'return'"), "{error}");
+    }
+
+    fn typed(declarations: &str, statements: &str) -> Result<Vec<String>, String> {
+        compiled(&format!("procedure main; {declarations} begin {statements} end; ."), Options::default())
+    }
+
+    #[test]
+    fn initializers_must_fit_their_array() {
+        let error = typed("var a size 2 = (1, 2, 3);", "").unwrap_err();
+        assert!(error.ends_with(": initializer of 3 elements does not fit in main.a (2 elements)"), "{error}");
+        let error = typed("var s: char size 2 = (1, 300);", "").unwrap_err();
+        assert!(error.ends_with(": 300 does not fit in an element of the char array main.s"), "{error}");
+        let error = typed("var b: boolean size 2 = (true, 1);", "").unwrap_err();
+        assert!(error.ends_with(": elements of the boolean array main.b must be true or false"), "{error}");
+    }
+
+    #[test]
+    fn loop_variables_are_not_assigned() {
+        let error = typed("var x;", "for x := 1 to 3 do x := 2").unwrap_err();
+        assert!(error.ends_with(": main.x is the variable of an enclosing for loop and cannot be assigned"), "{error}");
+    }
+
+    #[test]
+    fn integers_only_go_into_chars_as_small_constants() {
+        let chars = "const NL = 10, BIG = 1000; var c: char, s: char size 4, i;";
+        assert!(typed(chars, "c := 'a'; s[0] := NL; s[1] := 255; s[2] := c; i := c + 1; s[3] := chr(i)").is_ok());
+        assert!(typed(chars, "s[0] := 1000").unwrap_err().contains("1000 does not fit in a char"));
+        assert!(typed(chars, "s[0] := BIG").unwrap_err().contains("1000 does not fit in a char"));
+        assert!(typed(chars, "s[0] := c + 1").unwrap_err().contains("cannot store an integer into a char"));
+        assert!(typed(chars, "c := i").unwrap_err().contains("cannot store an integer into a char"));
+    }
+
+    #[test]
+    fn chars_are_checked_when_passed_and_returned() {
+        let asm = compiled("function up(c: char): char; begin return chr(c - 32) end; procedure main; var c: char, i; begin c := up('a'); i := up(c) end; .", Options::default()).unwrap();
+        assert!(emits(&asm, "andi a2, a2, 0xff"));
+        let error = compiled("function up(c: char): char; begin return c - 32 end; procedure main; begin end; .", Options::default()).unwrap_err();
+        assert!(error.contains("cannot store an integer into a char"));
+        let error = compiled("procedure put(c: char); begin end; procedure main; var i; begin call put(i) end; .", Options::default()).unwrap_err();
+        assert!(error.contains("cannot store an integer into a char"));
+        assert!(typed("var c: char, i;", "c := 'x'; i := 300; if c < i then c := 'y'").is_ok());
+    }
+
+    #[test]
+    fn device_constants_are_evaluated_or_rejected() {
+        let constants = crate::parser::device_constants("BASE = 0x10 ; the first\nNEXT = BASE + 2 * 4\n").unwrap();
+        assert_eq!(constants, vec![("global.BASE".to_string(), 16), ("global.NEXT".to_string(), 24)]);
+        assert!(crate::parser::device_constants("X = Y + 1").unwrap_err().contains("line 1: cannot evaluate Y in X"));
+    }
+
+    #[test]
+    fn runtime_routines_are_copied_when_used() {
+        let (code, data) = crate::runtime::runtime(&[]);
+        assert!(emits(&code, ".puts:"));
+        assert!(!emits(&code, ".strcpy:") && !emits(&code, ".wstrcpy:") && !emits(&code, "PL0_HEAP:"));
+        assert!(emits(&data, ".digits:") && !emits(&code, ".digits:"));
+        let (code, data) = crate::runtime::runtime(&["\tjal ra, crt0.strcpy".to_string(), "\tjal ra, PL0_HEAP.new".to_string()]);
+        assert!(emits(&code, ".strcpy:") && !emits(&code, ".wstrcpy:"));
+        assert!(emits(&data, ".free:") && emits(&data, ".top:") && !emits(&code, ".top:"));
+    }
+
+    fn linked(source: &str, layout: &str) -> Result<Vec<String>, String> {
+        let mut settings = Layout::default();
+        for setting in layout.split(',').filter(|setting| !setting.is_empty()) {
+            let (key, value) = setting.split_once('=').unwrap();
+            settings.set(key.trim(), value)?;
+        }
+        crate::link(compiled(source, Options::default())?, &settings)
+    }
+
+    #[test]
+    fn arrays_are_sized_by_their_bytes() {
+        let asm = compiled("const N = 10; var a size N, b: char size 2, N, c: char size 5 = 'ab'; procedure main; begin end; .", Options::default()).unwrap();
+        assert!(emits(&asm, "a: #res 40"));
+        assert!(emits(&asm, "b: #res 4"));
+        assert!(emits(&asm, "#res 5")); // the initializer takes 3 of the 8 bytes of c
+    }
+
+    #[test]
+    fn layouts_must_hold_the_program() {
+        let program = "var big size 0x4000; procedure main; begin big[0] := 1 end; .";
+        let error = |layout| linked(program, layout).err().unwrap_or_default();
+        assert!(linked(program, "").is_ok());
+        assert!(linked(program, "memory = 0x20000").is_ok());
+        assert!(error("memory = 0x10000").contains("Layout error: the program takes memory up to"));
+        assert!(error("code = 0x100, data = 0x200").contains("overlap the code"));
+        assert!(error("stack = 6").contains("multiple of 4"));
+    }
+
+    #[test]
+    fn reservations_must_be_numbers() {
+        let program = "procedure main; begin asm #res SIZE end end; .";
+        assert!(linked(program, "").unwrap_err().contains("cannot size '#res SIZE'"));
+    }
+
+    fn warnings(source: &str) -> Vec<String> {
+        let mut warnings = vec![];
+        compile("\t", tokenize(source.to_string()).unwrap(), &mut vec![], &mut warnings, Options::default()).unwrap();
+        warnings
+    }
+
+    #[test]
+    fn constant_divisors_of_zero_are_warned_about() {
+        let found = warnings("const NONE = 0; var x; procedure main; begin x := x / 0;\n x := x mod NONE; x := x / 2 end; .");
+        assert_eq!(found.len(), 2);
+        assert!(found[0].starts_with("Warning (1, ") && found[0].ends_with("division by zero, the divisor is the constant 0"));
+        assert!(found[1].starts_with("Warning (2, "));
+        assert!(warnings("var x; procedure main; begin x := x / (0 + x) end; .").is_empty());
+    }
+
+    #[test]
+    fn checked_divisions_trap() {
+        let program = "var x, y; procedure main; begin x := x / y; y := x mod 3 end; .";
+        let asm = compiled(program, Options { div_check: true, ..Options::default() }).unwrap();
+        assert_eq!(asm.iter().filter(|line| line.contains("beq a2, zero, PL0_TRAP.division")).count(), 2);
+        assert!(!emits(&compiled(program, Options::default()).unwrap(), "PL0_TRAP.division"));
+    }
+
+    #[test]
+    fn warnings_of_units_name_their_file() {
+        let dir = std::env::temp_dir().join(format!("pl0-warnings-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("halves.pl0"), "function half(x: integer): integer; begin return x / 0 end; .").unwrap();
+        let mut warnings = vec![];
+        let options = Options { path: vec![dir.clone()], ..Options::default() };
+        compile("\t", tokenize("uses halves; procedure main; begin end; .".to_string()).unwrap(), &mut vec![], &mut warnings, options).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with(&format!("{}: Warning (1, ", dir.join("halves.pl0").display())));
+    }
+
+    #[test]
+    fn checked_procedures_compare_the_stack_on_entry() {
+        let program = "procedure deeper; begin call deeper end; procedure main; begin call deeper end; .";
+        let asm = compiled(program, Options { stack_check: true, ..Options::default() }).unwrap();
+        let entry = asm.iter().position(|line| line == "deeper:").unwrap();
+        let check = asm.iter().position(|line| line.trim() == "bltu sp, t1, PL0_TRAP.stack").unwrap();
+        assert!(check > entry && asm[check - 1].trim() == "la t1, global.stack_limit");
+        assert!(emits(&asm, "#d \"deeper\\0\""));
+        assert_eq!(asm.iter().filter(|line| line.contains("PL0_TRAP.stack")).count(), 2);
+        assert!(!emits(&compiled(program, Options::default()).unwrap(), "PL0_TRAP.stack"));
+    }
+
+    #[test]
+    fn checked_indices_are_compared_with_the_length() {
+        let program = "var s size 4, m size 2 size 3; procedure main; var i; begin s[i] := 1; i := m[1, i] end; .";
+        let asm = compiled(program, Options { bounds_check: true, ..Options::default() }).unwrap();
+        assert_eq!(asm.iter().filter(|line| line.trim() == "bgeu a2, a3, PL0_TRAP.bounds").count(), 3);
+        assert!(emits(&asm, "llw a3, global.s.len"));
+        assert!(emits(&asm, "llw a3, global.m.len1"));
+        assert!(emits(&asm, "#d \"s\\0\""));
+        assert!(!emits(&compiled(program, Options::default()).unwrap(), "PL0_TRAP.bounds"));
+    }
+}
@@ -1,6 +1,5 @@
 #include "master.asm"
 #include "sys.asm"
-# addr 0
 crt0:
     li  a0, le(0b1_1_0_010_111110_11111111_000000000000)
     ssreg a0    ; supervisor, intterupt enabled, mmu disabled
@@ -56,6 +55,30 @@ crt0:
             ...end:
                 jalr zero, 0(ra)
 
+    .strcpy:                        ; Copy String (a0: char* dest, a1: char* src)
+        ..loop:
+            lbu t1, 0(a1)
+            sb t1, 0(a0)
+            beq t1, zero, ...end
+            addi a0, a0, 1
+            addi a1, a1, 1
+            j ..loop
+
+            ...end:
+                jalr zero, 0(ra)
+
+    .wstrcpy:                       ; Copy String into words (a0: int* dest, a1: char* src)
+        ..loop:
+            lbu t1, 0(a1)
+            sw t1, 0(a0)
+            beq t1, zero, ...end
+            addi a0, a0, 4
+            addi a1, a1, 1
+            j ..loop
+
+            ...end:
+                jalr zero, 0(ra)
+
     .exit:
         push a0, sp
         la a0, ..msg
@@ -135,7 +158,7 @@ PL0_INPUT:
         
 PL0_OUTPUT:
     addi t1, zero, 11
-    la t3, global.out_buff
+    la t3, PL0_DATA.digits
     fill t3, t1, zero
     addi t3, t3, 10
     addi t1, zero, 10
@@ -170,6 +193,153 @@ PL0_OUTPUT:
         pop ra, sp
         jalr zero, 0(ra)
 
+;---------------------------------------
+;   Run time checks of the generated code
+;---------------------------------------
+EXIT_BOUNDS = 0xb0
+EXIT_NIL = 0x4e
+EXIT_MEMORY = 0x40
+EXIT_STACK = 0x53
+EXIT_DIVISION = 0xd0
+
+PL0_TRAP:
+    .bounds:                        ; Index out of bounds (a0: site, a1: index)
+        mv t5, a0                   ; the site holds the source line, then the array name
+        mv t6, a1
+        la a0, ..msg
+        jal ra, crt0.puts
+        addi a0, t5, 4
+        jal ra, crt0.puts
+        addi a0, zero, 0x5b         ; '['
+        sbd a0, T_TX(zero)
+        mv a0, t6
+        jal ra, PL0_OUTPUT
+        la a0, ..line
+        jal ra, crt0.puts
+        lw a0, 0(t5)
+        jal ra, PL0_OUTPUT
+        addi a0, zero, 10
+        sbd a0, T_TX(zero)
+        addi a0, zero, EXIT_BOUNDS
+        j crt0.exit
+        ..msg:
+            #d "Trap: array index out of bounds: \0"
+            #align 32
+        ..line:
+            #d "] at line \0"
+            #align 32
+
+    .nil:                           ; Nil pointer dereference (a0: line)
+        mv t5, a0
+        la a0, ..msg
+        jal ra, crt0.puts
+        mv a0, t5
+        jal ra, PL0_OUTPUT
+        addi a0, zero, 10
+        sbd a0, T_TX(zero)
+        addi a0, zero, EXIT_NIL
+        j crt0.exit
+        ..msg:
+            #d "Trap: nil pointer dereference at line \0"
+            #align 32
+
+    .memory:                        ; The heap is exhausted
+        la a0, ..msg
+        jal ra, crt0.puts
+        addi a0, zero, 10
+        sbd a0, T_TX(zero)
+        addi a0, zero, EXIT_MEMORY
+        j crt0.exit
+        ..msg:
+            #d "Trap: out of memory\0"
+            #align 32
+
+    .stack:                         ; Stack overflow (a0: procedure name)
+        la sp, global.stack         ; room to report it
+        mv t5, a0
+        la a0, ..msg
+        jal ra, crt0.puts
+        mv a0, t5
+        jal ra, crt0.puts
+        addi a0, zero, 10
+        sbd a0, T_TX(zero)
+        addi a0, zero, EXIT_STACK
+        j crt0.exit
+        ..msg:
+            #d "Trap: stack overflow in \0"
+            #align 32
+
+    .division:                      ; Division by zero (a0: site)
+        mv t5, a0                   ; the site holds the source line, then the procedure name
+        la a0, ..msg
+        jal ra, crt0.puts
+        addi a0, t5, 4
+        jal ra, crt0.puts
+        la a0, ..line
+        jal ra, crt0.puts
+        lw a0, 0(t5)
+        jal ra, PL0_OUTPUT
+        addi a0, zero, 10
+        sbd a0, T_TX(zero)
+        addi a0, zero, EXIT_DIVISION
+        j crt0.exit
+        ..msg:
+            #d "Trap: division by zero in \0"
+            #align 32
+        ..line:
+            #d " at line \0"
+            #align 32
+
+;---------------------------------------
+;   Dynamic memory
+;---------------------------------------
+; Blocks carry their size in the word before the address handed out.
+; Freed blocks are linked through their first word, and new takes the
+; first one that is large enough before growing into the rest of the heap.
+PL0_HEAP:
+    .new:                           ; Allocate zeroed memory (a0: size) -> a0: address
+        addi a0, a0, 7              ; room for the size, rounded up to words
+        shirl a0, a0, 2
+        shill a0, a0, 2
+        la t1, PL0_DATA.free
+        ..first_fit:
+            lw t2, 0(t1)
+            beq t2, zero, ..grow
+            lw t3, -4(t2)
+            bgeu t3, a0, ..reuse
+            mv t1, t2
+            j ..first_fit
+
+        ..reuse:
+            lw t3, 0(t2)            ; unlink the block
+            sw t3, 0(t1)
+            mv a0, t2
+            lw t1, -4(a0)
+            subi t1, t1, 4
+            j ..clear
+
+        ..grow:
+            llw t2, PL0_DATA.top
+            add t3, t2, a0
+            la t4, global.heap_end
+            bltu t4, t3, PL0_TRAP.memory
+            ssw t3, PL0_DATA.top, t4
+            subi t1, a0, 4
+            sw a0, 0(t2)
+            addi a0, t2, 4
+
+        ..clear:
+            fill a0, t1, zero
+            jalr zero, 0(ra)
+
+    .dispose:                       ; Free memory from new (a0: address)
+        beq a0, zero, ..end
+        llw t1, PL0_DATA.free
+        sw t1, 0(a0)
+        ssw a0, PL0_DATA.free, t1
+        ..end:
+            jalr zero, 0(ra)
+
 ;---------------------------------------
 ;   Interrupt and Exception Handlers
 ;---------------------------------------
@@ -250,5 +420,16 @@ HANDLER_TPS_LOAD_FINISHED:
 HANDLER_DISK_LOAD_FINISHED:
     sysret                       ; Does nothing in PL/0
 
+;---------------------------------------
+;   Data of the runtime, placed with those of the program
+;---------------------------------------
+PL0_DATA:
+    .digits:                        ; PL0_OUTPUT: the digits, from the end, and a NUL
+        #res 12
+        #align 32
 
+    .free:                          ; PL0_HEAP: the first freed block
+        #d32 0
 
+    .top:                           ; PL0_HEAP: where the heap grows next
+        #d32 global.heap